@@ -1,15 +1,45 @@
 use argparse::ArgumentParser;
 
-use std::fs::File;
 use std::path::PathBuf;
 
+use lc_make::jobserver::JobserverStyle;
 use lc_make::loader::MakeFileLoader;
+use lc_make::output::OutputSync;
 
-fn main() -> std::io::Result<()> {
+fn main() {
+    // errors are already formatted as make diagnostics
+    match run() {
+        Ok(true) => {}
+        // -q found something out of date
+        Ok(false) => std::process::exit(1),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// runs make, returning whether the goals are up to date
+fn run() -> std::io::Result<bool> {
     let mut dir = None::<PathBuf>;
     let mut file = None::<PathBuf>;
+    let mut include_dirs = Vec::<PathBuf>::new();
     let mut silent = false;
-    let mut target = None::<String>;
+    let mut strict_cycles = false;
+    let mut keep_going = false;
+    let mut ignore_errors = false;
+    let mut dry_run = false;
+    let mut touch = false;
+    let mut question = false;
+    let mut always_make = false;
+    let mut print_data_base = false;
+    let mut assume_old = Vec::<String>::new();
+    let mut assume_new = Vec::<String>::new();
+    let mut jobs = None::<usize>;
+    let mut max_load = 0.0;
+    let mut jobserver_style = String::from("fifo");
+    let mut output_sync = String::from("none");
+    let mut targets = Vec::<String>::new();
     let mut ap = ArgumentParser::new();
     ap.refer(&mut dir).add_option(
         &["-C"],
@@ -21,51 +51,262 @@ fn main() -> std::io::Result<()> {
         argparse::StoreOption,
         "Use <file> as the Makefile instead of Makefile",
     );
-    ap.refer(&mut target)
-        .add_argument("TARGET", argparse::ParseOption, "TARGET to build");
+    ap.refer(&mut include_dirs).add_option(
+        &["-I", "--include-dir"],
+        argparse::Collect,
+        "Search <dir> for included makefiles",
+    );
+    ap.refer(&mut targets)
+        .add_argument("TARGET", argparse::List, "TARGETs to build, in order");
     ap.refer(&mut silent).add_option(
         &["--silent", "-s", "--quiet"],
         argparse::StoreTrue,
         "Prevents make from outputting anything",
     );
-    ap.parse_args_or_exit();
+    ap.refer(&mut strict_cycles).add_option(
+        &["--strict-cycles"],
+        argparse::StoreTrue,
+        "Fail on circular dependencies instead of dropping them",
+    );
+    ap.refer(&mut keep_going)
+        .add_option(
+            &["-k", "--keep-going"],
+            argparse::StoreTrue,
+            "Keep going when some targets can't be made",
+        )
+        .add_option(
+            &["-S", "--no-keep-going", "--stop"],
+            argparse::StoreFalse,
+            "Stop at the first target which can't be made",
+        );
+    ap.refer(&mut ignore_errors).add_option(
+        &["-i", "--ignore-errors"],
+        argparse::StoreTrue,
+        "Ignore errors from recipes",
+    );
+    ap.refer(&mut dry_run).add_option(
+        &["-n", "--just-print", "--dry-run", "--recon"],
+        argparse::StoreTrue,
+        "Print the recipes which would be run without running them",
+    );
+    ap.refer(&mut touch).add_option(
+        &["-t", "--touch"],
+        argparse::StoreTrue,
+        "Touch targets instead of remaking them",
+    );
+    ap.refer(&mut question).add_option(
+        &["-q", "--question"],
+        argparse::StoreTrue,
+        "Run nothing, exiting with 1 if anything is out of date",
+    );
+    ap.refer(&mut always_make).add_option(
+        &["-B", "--always-make"],
+        argparse::StoreTrue,
+        "Remake every target, even those which are up to date",
+    );
+    ap.refer(&mut print_data_base).add_option(
+        &["-p", "--print-data-base"],
+        argparse::StoreTrue,
+        "Print the variables and rules read from the makefiles",
+    );
+    ap.refer(&mut assume_old).add_option(
+        &["-o", "--old-file", "--assume-old"],
+        argparse::Collect,
+        "Treat <file> as very old and never remake it",
+    );
+    ap.refer(&mut assume_new).add_option(
+        &["-W", "--what-if", "--new-file", "--assume-new"],
+        argparse::Collect,
+        "Treat <file> as though it had just been modified",
+    );
+    ap.refer(&mut jobs).add_option(
+        &["-j", "--jobs"],
+        argparse::StoreOption,
+        "Run up to N recipes at once, or any number without N",
+    );
+    ap.refer(&mut max_load).add_option(
+        &["-l", "--max-load", "--load-average"],
+        argparse::Store,
+        "Don't start jobs while the load average is at least N, or without N, remove the limit",
+    );
+    ap.refer(&mut jobserver_style).add_option(
+        &["--jobserver-style"],
+        argparse::Store,
+        "Share jobs with sub-makes through a fifo or a pipe",
+    );
+    ap.refer(&mut output_sync).add_option(
+        &["-O", "--output-sync"],
+        argparse::Store,
+        "Keep the output of each job together, by line, target or recurse, or target without a type",
+    );
+    if let Err(code) = ap.parse(
+        normalise_args(with_makeflags(std::env::args())),
+        &mut std::io::stdout(),
+        &mut std::io::stderr(),
+    ) {
+        std::process::exit(code);
+    }
     drop(ap);
+    let jobserver_style = match jobserver_style.as_str() {
+        "fifo" => JobserverStyle::Fifo,
+        "pipe" => JobserverStyle::Pipe,
+        style => {
+            return Err(std::io::Error::other(format!(
+                "lc-make: *** unknown jobserver auth style '{}'.  Stop.",
+                style
+            )))
+        }
+    };
+    let output_sync = OutputSync::from_name(&output_sync).ok_or_else(|| {
+        std::io::Error::other(format!(
+            "lc-make: *** unknown output-sync type '{}'.  Stop.",
+            output_sync
+        ))
+    })?;
     if let Some(dir) = dir {
-        std::env::set_current_dir(dir)?;
+        std::env::set_current_dir(&dir).map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("lc-make: *** {}: {}.  Stop.", dir.display(), err),
+            )
+        })?;
     }
-    let file = if let Some(file) = file {
-        File::open(file)
-    } else {
+    let file = file.or_else(|| {
         let defaults = vec!["GNUmakefile", "makefile", "Makefile"];
 
         defaults
             .into_iter()
-            .map(File::open)
-            .find(Result::is_ok)
-            .ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::NotFound, "Cannot find makefile")
-            })
-            .and_then(|s| s)
+            .map(PathBuf::from)
+            .find(|path| path.exists())
+    });
+
+    // makefiles are read again whenever one of them is remade
+    let mut restarts = 0;
+    let makefile = loop {
+        // create a new makefile loader
+        let mut loader = MakeFileLoader::new();
+        loader.set_goals(&targets);
+        if restarts > 0 {
+            loader.set_restarts(restarts);
+        }
+        for dir in &include_dirs {
+            loader.add_include_dir(dir);
+        }
+
+        // makefiles named in the environment are read before any other
+        loader.load_env_makefiles()?;
+
+        // if we have a makefile then load its contents
+        if let Some(file) = &file {
+            loader.load_file(file)?;
+        }
+
+        // finalse the loaded makefile
+        let mut makefile = loader.finalise();
+        makefile.set_strict_cycles(strict_cycles);
+        makefile.set_keep_going(keep_going);
+        makefile.set_ignore_errors(ignore_errors);
+        makefile.set_dry_run(dry_run);
+        makefile.set_touch(touch);
+        makefile.set_always_make(always_make);
+        makefile.set_print_data_base(print_data_base);
+        for file in &assume_old {
+            makefile.assume_old(file);
+        }
+        for file in &assume_new {
+            makefile.assume_new(file);
+        }
+        // without -j, a parent make's jobserver is shared
+        if let Some(jobs) = jobs {
+            makefile.set_jobs(if jobs == 0 { None } else { Some(jobs) });
+        }
+        makefile.set_max_load(Some(max_load));
+        makefile.set_jobserver_style(jobserver_style);
+        makefile.set_output_sync(output_sync);
+        if !makefile.remake_makefiles(silent)? {
+            break makefile;
+        }
+        restarts += 1;
     };
 
-    // create a new makefile loader
-    let mut loader = MakeFileLoader::new();
+    if question {
+        return makefile.is_up_to_date(&targets);
+    }
 
-    // if we have a valid file then load the makefile's contents
-    if let Ok(mut file) = file {
-        loader.load(&mut file)?;
+    // perform the build
+    makefile.build_goals(&targets, silent)?;
+    Ok(true)
+}
+
+/// adds the options passed down by a parent make through MAKEFLAGS, ahead
+/// of those given on the command line so that those take precedence
+fn with_makeflags(mut args: impl Iterator<Item = String>) -> Vec<String> {
+    // the single letter flags which can be inherited
+    const LETTERS: &str = "Biknpqst";
+
+    let mut result: Vec<String> = args.next().into_iter().collect();
+    let makeflags = std::env::var("MAKEFLAGS").unwrap_or_default();
+    for (i, word) in makeflags.split_whitespace().enumerate() {
+        if word == "--" {
+            break;
+        } else if i == 0 && !word.starts_with('-') {
+            let letters = word.chars().filter(|c| LETTERS.contains(*c));
+            result.extend(letters.map(|c| format!("-{}", c)));
+        } else if word.starts_with("-O")
+            || word.starts_with("--output-sync")
+            || word.starts_with("--assume-")
+        {
+            result.push(word.to_owned());
+        }
     }
+    result.extend(args);
+    result
+}
 
-    // finalse the loaded makefile
-    let makefile = loader.finalise();
+/// rewrites arguments whose values are optional, which argparse can't handle,
+/// so that a bare option is passed with its default value
+fn normalise_args(args: Vec<String>) -> Vec<String> {
+    // short option, long option, default value, whether the short option
+    // takes its value from the next argument, and which values it takes
+    type Optional = (
+        &'static str,
+        &'static str,
+        &'static str,
+        bool,
+        fn(&str) -> bool,
+    );
+    const OPTIONAL: &[Optional] = &[
+        ("-j", "--jobs", "0", true, is_number),
+        ("-l", "--max-load", "0", true, is_number),
+        ("-O", "--output-sync", "target", false, is_output_sync),
+    ];
 
-    // perform the build
-    if let Some(target) = target {
-        // don't be silent for debugging purposes
-        makefile.build_target(target, silent);
-    } else {
-        makefile.build_default(silent);
+    let mut result = Vec::new();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let option = OPTIONAL.iter().find(|(short, long, _, _, _)| {
+            arg == *short || arg == *long || arg.starts_with(short) && arg.len() > 2
+        });
+        match option {
+            Some((short, long, default, separate, is_value)) if arg == *short || arg == *long => {
+                match args.peek() {
+                    Some(next) if (arg == *long || *separate) && is_value(next) => result.push(arg),
+                    _ => result.push(format!("{}={}", long, default)),
+                }
+            }
+            Some((short, long, _, _, is_value)) if is_value(&arg[short.len()..]) => {
+                result.push(format!("{}={}", long, &arg[short.len()..]))
+            }
+            _ => result.push(arg),
+        }
     }
+    result
+}
+
+fn is_number(arg: &str) -> bool {
+    arg.parse::<f64>().is_ok()
+}
 
-    Ok(())
+fn is_output_sync(arg: &str) -> bool {
+    OutputSync::from_name(arg).is_some()
 }
@@ -0,0 +1,1217 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::process::Command;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::expand::{error, expand, located, message_at, Location, Scope};
+use crate::functions::{shell_command, status_code};
+use crate::jobserver::{Jobserver, JobserverStyle};
+use crate::makefile::{FinalRule, MakeFile, RecipeScope};
+use crate::output::{same_output, Output, OutputSync};
+use crate::variables::{Origin, Variable};
+
+// the prerequisite which makes the prerequisites after it wait for those before it
+const WAIT: &str = ".WAIT";
+
+// the single letter flags in MAKEFLAGS which make sets itself
+const MAKEFLAGS_LETTERS: &str = "Biknpqst";
+
+// how often a make waiting for a jobserver token or the load to fall checks again
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// how much of the load average a job started in the last second is assumed to add
+const LOAD_WEIGHT_A: f64 = 0.25;
+const LOAD_WEIGHT_B: f64 = 1.0 - LOAD_WEIGHT_A;
+
+/// Options which change how targets are brought up to date
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BuildOptions {
+    pub(crate) jobs: Option<usize>, // The most jobs run at once, None means unlimited
+    pub(crate) max_load: Option<f64>, // No jobs are started while the load average is higher
+    pub(crate) jobs_given: bool, // Whether jobs was chosen, rather than sharing a parent's jobserver
+    pub(crate) jobserver_style: JobserverStyle,
+    pub(crate) output_sync: OutputSync,
+    pub(crate) strict_cycles: bool, // Whether circular dependencies are errors rather than dropped
+    pub(crate) keep_going: bool,    // Whether targets unaffected by a failure are still updated
+    pub(crate) ignore_errors: bool, // Whether every recipe line is run as though prefixed with -
+    pub(crate) dry_run: bool,       // Whether recipe lines are printed rather than run
+    pub(crate) touch: bool,         // Whether targets are touched rather than remade
+    pub(crate) question: bool,      // Whether nothing is run, only checking what's out of date
+    pub(crate) always_make: bool,   // Whether every target with a rule is out of date
+    pub(crate) print_data_base: bool, // Whether everything make knows is printed after a run
+    pub(crate) assume_old: Vec<String>, // Files treated as very old, which are never remade
+    pub(crate) assume_new: Vec<String>, // Files treated as though they had just been modified
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            jobs: Some(1),
+            max_load: None,
+            jobs_given: false,
+            jobserver_style: JobserverStyle::Fifo,
+            output_sync: OutputSync::None,
+            strict_cycles: false,
+            keep_going: false,
+            ignore_errors: false,
+            dry_run: false,
+            touch: false,
+            question: false,
+            always_make: false,
+            print_data_base: false,
+            assume_old: Vec::new(),
+            assume_new: Vec::new(),
+        }
+    }
+}
+
+impl BuildOptions {
+    /// the options used when remaking makefiles, which are always really
+    /// updated so that they can be read, and only when out of date so that
+    /// make doesn't restart forever
+    pub(crate) fn for_makefiles(&self) -> Self {
+        Self {
+            dry_run: false,
+            touch: false,
+            question: false,
+            always_make: false,
+            print_data_base: false,
+            ..self.clone()
+        }
+    }
+
+    /// checks whether a file is treated as very old by -o
+    fn is_old(&self, name: &str) -> bool {
+        self.assume_old.iter().any(|file| file == name)
+    }
+
+    /// checks whether a file is treated as just modified by -W
+    fn is_new(&self, name: &str) -> bool {
+        self.assume_new.iter().any(|file| file == name)
+    }
+}
+
+// the load limit is never NaN
+impl Eq for BuildOptions {}
+
+/// The error returned when a target couldn't be brought up to date, which
+/// carries the diagnostic make reports for it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BuildError {
+    message: String, // The diagnostic, as make prints it
+    target: Option<String>,
+    status: Option<i32>, // The exit status of the recipe line which failed
+}
+
+impl BuildError {
+    pub(crate) fn new(message: String, target: Option<&str>, status: Option<i32>) -> Self {
+        Self {
+            message,
+            target: target.map(str::to_owned),
+            status,
+        }
+    }
+
+    /// the target which couldn't be brought up to date
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    /// the exit status of the recipe line which failed, if one did
+    pub fn status(&self) -> Option<i32> {
+        self.status
+    }
+
+    /// finds the build error carried by an I/O error, if it carries one
+    pub fn find(err: &io::Error) -> Option<&BuildError> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for BuildError {}
+
+impl From<BuildError> for io::Error {
+    fn from(err: BuildError) -> Self {
+        io::Error::other(err)
+    }
+}
+
+/// How far a target has got during a run of make
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Status {
+    Running,                     // Its recipe is being run by a job
+    Updated(String, SystemTime), // The path it was found at and when it was last modified
+    Failed,                      // It, or one of its prerequisites, couldn't be updated
+}
+
+/// A recipe line which is ready to be run
+struct JobLine {
+    command: Command,
+    echo: Option<String>, // The line to print before running it, unless silenced
+    location: Option<Location>,
+    recursive: bool,     // Whether it runs a sub-make, either through $(MAKE) or +
+    ignore_errors: bool, // Whether the recipe carries on if it fails, as with -
+    run: bool,           // Whether it's run, rather than only printed by -n
+}
+
+/// The result of a job, sent back once its recipe has finished
+type Finished = (String, String, Result<(), JobFailure>); // Target, path, result
+
+/// The line of a recipe which failed and its exit status
+type JobFailure = (Option<Location>, i32);
+
+/// A target whose recipe can't be run yet, with how far through its
+/// prerequisites it has got
+struct Waiting<'a> {
+    rule: &'a FinalRule,
+    next: usize,    // The index of the next prerequisite to poll
+    pending: usize, // Prerequisites polled which aren't up to date yet
+    deferred: bool, // Whether it's queued to carry on once a job finishes
+    failed: bool,   // Whether a prerequisite couldn't be updated, when keeping going
+}
+
+/// A target whose prerequisites are up to date, waiting for a job to run its recipe
+struct Ready<'a> {
+    rule: &'a FinalRule,
+    path: String,
+    deps: Vec<String>, // The paths of its prerequisites
+}
+
+/// How far polling the prerequisites of a target got
+enum Progress {
+    Poll(String), // A prerequisite needs polling itself before carrying on
+    Waiting,      // Some prerequisites aren't up to date yet
+    Failed,       // A prerequisite couldn't be updated
+    Done,         // Every prerequisite is up to date
+}
+
+/// A single run of make over the dependency graph, which remembers every
+/// target it has considered so that each is only updated once, and runs
+/// the recipes of independent targets as concurrent jobs
+///
+/// Targets are polled depth first without recursing, so that long chains of
+/// prerequisites can't overflow the stack: `updating` holds the targets part
+/// way through their prerequisites, each waiting on the one after it, and
+/// `notify` the targets to tell once a prerequisite has settled.
+pub(crate) struct Run<'a> {
+    makefile: &'a MakeFile,
+    options: BuildOptions,
+    silent: bool,
+    jobs: usize,
+    status: HashMap<String, Status>,
+    updating: Vec<String>,
+    polling: HashSet<String>, // The targets in updating, to find cycles quickly
+    waiting: HashMap<String, Waiting<'a>>,
+    dependents: HashMap<String, Vec<String>>, // The targets waiting on each target
+    notify: Vec<(String, bool)>, // Targets whose prerequisite settled, and whether it failed
+    ready: VecDeque<Ready<'a>>,
+    deferred: VecDeque<String>, // Targets left part way through their prerequisites
+    next_goal: usize,
+    dropped: HashSet<(String, String)>, // Circular dependencies which have been reported
+    running: usize,
+    failure: Option<io::Error>, // Once set, no more jobs are started
+    failed: Vec<(String, Option<i32>)>, // Targets which failed when keeping going, with their exit status
+    sender: Sender<Finished>,
+    receiver: Receiver<Finished>,
+    jobserver: Option<Jobserver>,
+    tokens: Vec<u8>, // Jobserver tokens held for running jobs, every job but one needs one
+    starved: bool,   // Whether a job is waiting for a jobserver token or the load to fall
+    recent_jobs: f64, // Jobs started recently, which the load average doesn't reflect yet
+    recent_second: u64, // The second recent_jobs was last decayed in
+    out_of_date: bool, // Whether -q found a target which needs remaking
+    makeflags: String, // MAKEFLAGS as passed to recipes
+    merged_output: bool, // Whether make's stdout and stderr are the same file
+}
+
+impl<'a> Run<'a> {
+    pub(crate) fn new(makefile: &'a MakeFile, options: BuildOptions, silent: bool) -> Self {
+        // .NOTPARALLEL without prerequisites makes the whole run serial
+        let not_parallel = makefile
+            .rule(".NOTPARALLEL")
+            .is_some_and(|rule| rule.prereqs().is_empty());
+        let mut jobs = match options.jobs {
+            _ if not_parallel => 1,
+            Some(jobs) => jobs.max(1),
+            None => usize::MAX,
+        };
+
+        // a sub-make which wasn't given -j shares the jobserver of its parent,
+        // whereas one which was runs its own, which needs unix pipes
+        let makeflags = makefile.variable("MAKEFLAGS").unwrap_or_default();
+        let jobserver = if not_parallel || !cfg!(unix) {
+            None
+        } else if !options.jobs_given {
+            match Jobserver::join(&makeflags) {
+                Some(Ok(jobserver)) => {
+                    jobs = usize::MAX;
+                    Some(jobserver)
+                }
+                Some(Err(err)) => {
+                    let message = format!(
+                        "warning: jobserver unavailable ({}): using -j1.  Add '+' to parent make rule.",
+                        err
+                    );
+                    eprintln!("{}", message_at(None, &message));
+                    None
+                }
+                None => None,
+            }
+        } else if jobs != usize::MAX {
+            match Jobserver::create(jobs, options.jobserver_style) {
+                Ok(jobserver) => Some(jobserver),
+                Err(err) => {
+                    let message = format!("warning: couldn't create the jobserver: {}", err);
+                    eprintln!("{}", message_at(None, &message));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // sub-makes are passed the options which apply to them through
+        // MAKEFLAGS, including how to reach a jobserver this make created
+        let created = jobserver
+            .as_ref()
+            .filter(|_| jobs != usize::MAX)
+            .map(|jobserver| (jobs, jobserver.auth()));
+        let makeflags = pass_makeflags(&makeflags, silent, &options, created);
+
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            makefile,
+            options,
+            silent,
+            jobs,
+            status: HashMap::new(),
+            updating: Vec::new(),
+            polling: HashSet::new(),
+            waiting: HashMap::new(),
+            dependents: HashMap::new(),
+            notify: Vec::new(),
+            ready: VecDeque::new(),
+            deferred: VecDeque::new(),
+            next_goal: 0,
+            dropped: HashSet::new(),
+            running: 0,
+            failure: None,
+            failed: Vec::new(),
+            sender,
+            receiver,
+            jobserver,
+            tokens: Vec::new(),
+            starved: false,
+            recent_jobs: 0.0,
+            recent_second: 0,
+            out_of_date: false,
+            makeflags,
+            merged_output: same_output(),
+        }
+    }
+
+    /// brings every goal up to date, running as many jobs at once as allowed
+    pub(crate) fn update(&mut self, goals: &[String]) -> io::Result<()> {
+        loop {
+            self.starved = false;
+            self.run_ready();
+            self.resume_deferred();
+            self.poll_goals(goals);
+            if self.running == 0 {
+                debug_assert!(self.next_goal == goals.len() || self.failure.is_some());
+                break;
+            }
+
+            // wait for a job to finish before looking for more to start, or
+            // for a token to become free or the load to fall if a job is
+            // waiting for either
+            let finished = if self.starved {
+                match self.receiver.recv_timeout(POLL_INTERVAL) {
+                    Ok(finished) => finished,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => panic!("job channel closed"),
+                }
+            } else {
+                self.receiver.recv().expect("job channel closed")
+            };
+            let (name, path, result) = finished;
+            self.running -= 1;
+            self.release_token();
+            match result.map(|()| self.touch(&name, &path)) {
+                Ok(Ok(())) => {
+                    self.status
+                        .insert(name.clone(), Status::Updated(path, SystemTime::now()));
+                }
+                Ok(Err(err)) => self.give_up(&name, err),
+                Err((location, status)) => {
+                    let message = format!("*** {}", job_error(&name, location.as_ref(), status));
+                    let err =
+                        BuildError::new(message_at(None, &message), Some(&name), Some(status));
+                    self.give_up(&name, err.into());
+                }
+            }
+            self.settled(&name);
+            self.walk();
+        }
+
+        if !self.failed.is_empty() {
+            return Err(self.summarise(goals).into());
+        }
+        match self.failure.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// checks whether a target is listed in .PHONY
+    fn is_phony(&self, name: &str) -> bool {
+        self.makefile
+            .rule(".PHONY")
+            .is_some_and(|phony| phony.prereqs().iter().any(|prereq| prereq == name))
+    }
+
+    /// how far a target got during the run, if it was considered at all
+    pub(crate) fn status(&self, name: &str) -> Option<&Status> {
+        self.status.get(name)
+    }
+
+    /// every target considered during the run
+    pub(crate) fn considered(&self) -> impl Iterator<Item = &String> {
+        self.status.keys()
+    }
+
+    /// whether any target was found to be out of date by -q
+    pub(crate) fn out_of_date(&self) -> bool {
+        self.out_of_date
+    }
+
+    /// marks a target as up to date for -t, once any sub-makes its recipe
+    /// runs have finished
+    fn touch(&self, name: &str, path: &str) -> io::Result<()> {
+        // phony targets aren't files, so there's nothing to touch
+        if !self.options.touch || self.is_phony(name) {
+            return Ok(());
+        }
+        if !self.silent {
+            println!("touch {}", path);
+        }
+        if self.options.dry_run {
+            return Ok(());
+        }
+        let file = File::options().create(true).append(true).open(path)?;
+        file.set_modified(SystemTime::now())
+    }
+
+    /// describes the goals which couldn't be updated when keeping going, and
+    /// the targets which failed
+    fn summarise(&self, goals: &[String]) -> BuildError {
+        let mut lines = Vec::new();
+        for goal in goals {
+            if self.status.get(goal) == Some(&Status::Failed) {
+                let message = format!("Target '{}' not remade because of errors.", goal);
+                lines.push(message_at(None, &message));
+            }
+        }
+        lines.push(message_at(None, "*** Failed targets:"));
+        for (name, status) in &self.failed {
+            let message = match status {
+                Some(status) => format!("***   {} (exit status {})", name, status),
+                None => format!("***   {}", name),
+            };
+            lines.push(message_at(None, &message));
+        }
+        BuildError::new(lines.join("\n"), None, None)
+    }
+
+    /// the path a target was found at, once it has been updated
+    pub(crate) fn path(&self, name: &str) -> Option<&str> {
+        match self.status.get(name) {
+            Some(Status::Updated(path, _)) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// checks whether the load average is too high to start another job,
+    /// which is never the case when no jobs are running
+    fn load_too_high(&mut self) -> bool {
+        let max_load = match self.options.max_load {
+            Some(max_load) if self.running > 0 => max_load,
+            _ => return false,
+        };
+        let load = match load_average() {
+            Some(load) => load,
+            None => return false,
+        };
+
+        // the load average lags behind, so jobs started in the last second
+        // or so are guessed to add to it, decaying as time goes on
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        if self.recent_second < now {
+            if self.recent_second + 1 == now {
+                self.recent_jobs *= LOAD_WEIGHT_B;
+            } else {
+                self.recent_jobs = 0.0;
+            }
+            self.recent_second = now;
+        }
+
+        let too_high = load + LOAD_WEIGHT_A * self.recent_jobs >= max_load;
+        self.starved |= too_high;
+        too_high
+    }
+
+    /// takes a jobserver token for a job, unless it's the only one running
+    fn acquire_token(&mut self) -> bool {
+        match &mut self.jobserver {
+            Some(jobserver) if self.running > 0 => match jobserver.try_acquire() {
+                Some(token) => {
+                    self.tokens.push(token);
+                    true
+                }
+                None => {
+                    self.starved = true;
+                    false
+                }
+            },
+            _ => true,
+        }
+    }
+
+    /// gives back a jobserver token once a job no longer needs it
+    fn release_token(&mut self) {
+        if let (Some(jobserver), Some(token)) = (&mut self.jobserver, self.tokens.pop()) {
+            jobserver.release(token);
+        }
+    }
+
+    /// marks a target as failed, recording why so that the failure can be
+    /// returned once running jobs have finished, or reporting it at once
+    /// when keeping going
+    fn give_up(&mut self, name: &str, err: io::Error) {
+        self.waiting.remove(name);
+        self.status.insert(name.to_owned(), Status::Failed);
+        let err = match BuildError::find(&err) {
+            Some(_) => err,
+            None => BuildError::new(located(err, None).to_string(), Some(name), None).into(),
+        };
+        if self.options.keep_going {
+            eprintln!("{}", err);
+            let status = BuildError::find(&err).and_then(BuildError::status);
+            self.failed.push((name.to_owned(), status));
+        } else {
+            self.fail(err);
+        }
+    }
+
+    /// records the first failure, after which running jobs are waited for,
+    /// while those jobs which fail meanwhile are reported as they finish
+    fn fail(&mut self, err: io::Error) {
+        if self.failure.is_some() {
+            eprintln!("{}", err);
+            return;
+        }
+        if self.running > 0 {
+            eprintln!(
+                "{}",
+                message_at(None, "*** Waiting for unfinished jobs....")
+            );
+        }
+        self.failure = Some(err);
+    }
+
+    /// polls the goals in order, stopping while every job is taken so that
+    /// a serial run updates them one after another
+    fn poll_goals(&mut self, goals: &[String]) {
+        while let Some(goal) = goals.get(self.next_goal) {
+            if self.failure.is_some() || self.running >= self.jobs {
+                break;
+            }
+            self.next_goal += 1;
+            if self.status.contains_key(goal) || self.waiting.contains_key(goal) {
+                continue;
+            }
+            match self.consider(goal) {
+                Ok(true) => self.enter(goal),
+                Ok(false) => {}
+                Err(err) => self.give_up(goal, err),
+            }
+            self.walk();
+        }
+    }
+
+    /// starts the jobs of targets waiting only for one, in the order they
+    /// became ready
+    fn run_ready(&mut self) {
+        while !self.ready.is_empty() && self.can_start() {
+            let ready = self.ready.pop_front().expect("ready queue is empty");
+            let name = ready.rule.target().to_owned();
+            if let Err(err) = self.launch(ready) {
+                self.give_up(&name, err);
+                self.settled(&name);
+                self.walk();
+            }
+        }
+    }
+
+    /// carries on polling the prerequisites of targets which stopped when
+    /// every job was taken, while there are jobs free
+    fn resume_deferred(&mut self) {
+        while self.failure.is_none() && self.running < self.jobs {
+            let name = match self.deferred.pop_front() {
+                Some(name) => name,
+                None => break,
+            };
+            // a target may have carried on since it was queued
+            if self
+                .waiting
+                .get(&name)
+                .is_some_and(|waiting| waiting.deferred)
+            {
+                self.enter(&name);
+                self.walk();
+            }
+        }
+    }
+
+    /// pushes a waiting target onto the targets being polled
+    fn enter(&mut self, name: &str) {
+        self.updating.push(name.to_owned());
+        self.polling.insert(name.to_owned());
+    }
+
+    /// carries on with the targets being polled, and then with the targets
+    /// waiting on those which have settled, until nothing more can be done
+    /// before a job finishes
+    fn walk(&mut self) {
+        loop {
+            if let Some(name) = self.updating.last().cloned() {
+                if let Some(prereq) = self.step(&name) {
+                    self.enter(&prereq);
+                    continue;
+                }
+                self.updating.pop();
+                self.polling.remove(&name);
+                if matches!(
+                    self.status.get(&name),
+                    Some(Status::Updated(_, _)) | Some(Status::Failed)
+                ) {
+                    self.settled(&name);
+                }
+            } else if let Some((name, failed)) = self.notify.pop() {
+                self.notified(&name, failed);
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// queues the targets waiting on a target to be told that it's up to
+    /// date or has failed, in the order they started waiting
+    fn settled(&mut self, name: &str) {
+        let failed = self.status.get(name) == Some(&Status::Failed);
+        let dependents = self.dependents.remove(name).unwrap_or_default();
+        self.notify.extend(
+            dependents
+                .into_iter()
+                .rev()
+                .map(|dependent| (dependent, failed)),
+        );
+    }
+
+    /// tells a waiting target that one of its prerequisites has settled,
+    /// carrying on with it once it's waiting on no others
+    fn notified(&mut self, name: &str, failed: bool) {
+        // a target may have already failed because of another prerequisite
+        let waiting = match self.waiting.get_mut(name) {
+            Some(waiting) => waiting,
+            None => return,
+        };
+        waiting.pending -= 1;
+        if failed && !self.options.keep_going {
+            self.waiting.remove(name);
+            self.status.insert(name.to_owned(), Status::Failed);
+            self.settled(name);
+            return;
+        }
+        // when keeping going the rest of its prerequisites are still updated
+        waiting.failed |= failed;
+        if waiting.pending == 0 && self.failure.is_none() {
+            self.enter(name);
+        }
+    }
+
+    /// looks at a target for the first time, finding the file of one without
+    /// a rule, and otherwise setting it waiting on its prerequisites, which
+    /// is when true is returned
+    fn consider(&mut self, name: &str) -> io::Result<bool> {
+        let makefile = self.makefile;
+        if self.options.is_old(name) {
+            let path = makefile.find_file(name)?.unwrap_or_else(|| name.to_owned());
+            self.status.insert(
+                name.to_owned(),
+                Status::Updated(path, SystemTime::UNIX_EPOCH),
+            );
+            return Ok(false);
+        }
+
+        let rule = match makefile.rule(name) {
+            Some(rule) => rule,
+            None => {
+                let path = match makefile.find_file(name)? {
+                    Some(path) => path,
+                    None => {
+                        let message = match self.updating.last() {
+                            Some(parent) => {
+                                format!("No rule to make target '{}', needed by '{}'", name, parent)
+                            }
+                            None => format!("No rule to make target '{}'", name),
+                        };
+                        // make only stops here when it isn't keeping going
+                        let message = if self.options.keep_going {
+                            format!("*** {}.", message)
+                        } else {
+                            format!("*** {}.  Stop.", message)
+                        };
+                        let err = BuildError::new(message_at(None, &message), Some(name), None);
+                        return Err(err.into());
+                    }
+                };
+                let modified = if self.options.is_new(name) {
+                    SystemTime::now()
+                } else {
+                    std::fs::metadata(&path)
+                        .and_then(|meta| meta.modified())
+                        .map_err(|err| located(err, None))?
+                };
+                self.status
+                    .insert(name.to_owned(), Status::Updated(path, modified));
+                return Ok(false);
+            }
+        };
+
+        let waiting = Waiting {
+            rule,
+            next: 0,
+            pending: 0,
+            deferred: false,
+            failed: false,
+        };
+        self.waiting.insert(name.to_owned(), waiting);
+        Ok(true)
+    }
+
+    /// carries on polling the prerequisites of the target at the top of
+    /// `updating`, returning a prerequisite which must be polled first, or
+    /// None once the target is done with until something changes
+    fn step(&mut self, name: &str) -> Option<String> {
+        let mut waiting = self.waiting.remove(name).expect("target isn't waiting");
+        waiting.deferred = false;
+        let result = match self.poll_prereqs(name, &mut waiting) {
+            Ok(Progress::Poll(prereq)) => {
+                self.waiting.insert(name.to_owned(), waiting);
+                return Some(prereq);
+            }
+            Ok(Progress::Waiting) => {
+                self.waiting.insert(name.to_owned(), waiting);
+                return None;
+            }
+            // the prerequisite's failure has already been recorded
+            Ok(Progress::Failed) => {
+                self.status.insert(name.to_owned(), Status::Failed);
+                return None;
+            }
+            Ok(Progress::Done) => self.start(waiting),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            self.give_up(name, err);
+        }
+        None
+    }
+
+    /// polls the prerequisites of a waiting target from where it got to
+    fn poll_prereqs(&mut self, name: &str, waiting: &mut Waiting<'a>) -> io::Result<Progress> {
+        let rule = waiting.rule;
+        // the prerequisites of targets listed in .NOTPARALLEL are updated one at a time
+        let serial = self
+            .makefile
+            .rule(".NOTPARALLEL")
+            .is_some_and(|not_parallel| not_parallel.prereqs().contains(&rule.target().to_owned()));
+
+        let prereqs = rule.prereqs();
+        while let Some(prereq) = prereqs.get(waiting.next) {
+            if (prereq == WAIT || serial) && (waiting.pending > 0 || waiting.failed) {
+                break;
+            }
+            if prereq == WAIT {
+                waiting.next += 1;
+                continue;
+            }
+            if self.failure.is_some() {
+                break;
+            }
+            // with every job taken the rest wait until one finishes, so that
+            // a serial run considers targets in order
+            if self.running >= self.jobs {
+                if !waiting.deferred {
+                    waiting.deferred = true;
+                    self.deferred.push_back(name.to_owned());
+                }
+                break;
+            }
+
+            if let Some(cycle) = self.cycle(prereq) {
+                if self.options.strict_cycles {
+                    let message = format!("Circular dependency {}", cycle.join(" <- "));
+                    return Err(located(error(message), None));
+                }
+                if self.dropped.insert((name.to_owned(), prereq.clone())) {
+                    let message = format!("Circular {} <- {} dependency dropped.", name, prereq);
+                    eprintln!("{}", message_at(None, &message));
+                }
+                waiting.next += 1;
+                continue;
+            }
+
+            match self.status.get(prereq) {
+                Some(Status::Updated(_, _)) => {}
+                // the other prerequisites are still updated when keeping going
+                Some(Status::Failed) if self.options.keep_going => waiting.failed = true,
+                Some(Status::Failed) => return Ok(Progress::Failed),
+                Some(Status::Running) => self.wait_on(name, prereq, waiting),
+                None if self.waiting.contains_key(prereq) => self.wait_on(name, prereq, waiting),
+                // the prerequisite is looked at again once it has been polled
+                None => match self.consider(prereq) {
+                    Ok(true) => return Ok(Progress::Poll(prereq.clone())),
+                    Ok(false) => continue,
+                    Err(err) => {
+                        self.give_up(prereq, err);
+                        continue;
+                    }
+                },
+            }
+            waiting.next += 1;
+        }
+
+        Ok(if waiting.pending > 0 {
+            Progress::Waiting
+        } else if waiting.failed {
+            Progress::Failed
+        } else if waiting.next < prereqs.len() {
+            Progress::Waiting
+        } else {
+            Progress::Done
+        })
+    }
+
+    /// sets a target waiting on a prerequisite which isn't up to date yet
+    fn wait_on(&mut self, name: &str, prereq: &str, waiting: &mut Waiting<'a>) {
+        waiting.pending += 1;
+        self.dependents
+            .entry(prereq.to_owned())
+            .or_default()
+            .push(name.to_owned());
+    }
+
+    /// finds the cycle polling a prerequisite would close, either because
+    /// it's being polled itself or because it's waiting, perhaps through
+    /// other targets, on one which is
+    fn cycle(&self, prereq: &str) -> Option<Vec<String>> {
+        if self.polling.contains(prereq) {
+            let start = self.updating.iter().position(|name| name == prereq)?;
+            let mut cycle = self.updating[start..].to_vec();
+            cycle.push(prereq.to_owned());
+            return Some(cycle);
+        }
+        if !self.waiting.contains_key(prereq) {
+            return None;
+        }
+
+        // only the target polling started from can have others waiting on
+        // it, as the rest were first looked at while being polled, so the
+        // targets waiting on it are searched, remembering what each was
+        // reached from
+        let first = self.updating.first()?.as_str();
+        let mut reached: HashMap<&str, &str> = HashMap::new();
+        let mut search = vec![first];
+        while let Some(target) = search.pop() {
+            for dependent in self.dependents.get(target).into_iter().flatten() {
+                let dependent = dependent.as_str();
+                if reached.contains_key(dependent) || self.polling.contains(dependent) {
+                    continue;
+                }
+                reached.insert(dependent, target);
+                if dependent != prereq {
+                    search.push(dependent);
+                    continue;
+                }
+
+                let mut cycle = vec![prereq.to_owned()];
+                let mut target = prereq;
+                while let Some(&from) = reached.get(target) {
+                    cycle.push(from.to_owned());
+                    target = from;
+                }
+                cycle.extend(self.updating[1..].iter().cloned());
+                cycle.push(prereq.to_owned());
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    /// checks whether a target whose prerequisites are up to date needs its
+    /// recipe running, and starts a job to do so or queues it until there's
+    /// one free
+    fn start(&mut self, waiting: Waiting<'a>) -> io::Result<()> {
+        let makefile = self.makefile;
+        let rule = waiting.rule;
+        let name = rule.target();
+
+        let mut deps = Vec::new();
+        let mut newest = SystemTime::UNIX_EPOCH;
+        for prereq in rule.prereqs() {
+            // prerequisites dropped as circular aren't passed to the recipe
+            if self.dropped.contains(&(name.to_owned(), prereq.clone())) {
+                continue;
+            }
+            if let Some(Status::Updated(path, modified)) = self.status.get(prereq) {
+                newest = std::cmp::max(*modified, newest);
+                deps.push(path.clone());
+            }
+        }
+
+        let found = makefile.find_file(name)?;
+        let modified = match &found {
+            Some(_) if self.options.is_new(name) => Some(SystemTime::now()),
+            Some(path) => std::fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .ok(),
+            None => None,
+        };
+
+        // phony targets are always out of date, even if a file has their name
+        if let (Some(path), Some(modified)) = (&found, modified) {
+            if newest < modified && !self.options.always_make && !self.is_phony(name) {
+                self.status
+                    .insert(name.to_owned(), Status::Updated(path.clone(), modified));
+                return Ok(());
+            }
+        }
+
+        // out of date targets found along the search path are rebuilt in the
+        // current directory, unless their directory is listed in GPATH
+        let path = match found {
+            Some(path) if path != name && makefile.in_gpath(&path, name)? => path,
+            _ => name.to_owned(),
+        };
+
+        // -q runs nothing, it only finds out whether anything needs running
+        if rule.recipes().is_empty() || self.options.question {
+            self.out_of_date |= !rule.recipes().is_empty();
+            self.status
+                .insert(name.to_owned(), Status::Updated(path, SystemTime::now()));
+            return Ok(());
+        }
+
+        // targets which became ready earlier are started first
+        let ready = Ready { rule, path, deps };
+        if self.ready.is_empty() && self.can_start() {
+            self.launch(ready)?;
+        } else {
+            self.ready.push_back(ready);
+            self.waiting.insert(name.to_owned(), waiting);
+        }
+        Ok(())
+    }
+
+    /// checks whether another job can be started, taking a jobserver token
+    /// for it if one is needed
+    fn can_start(&mut self) -> bool {
+        self.failure.is_none()
+            && self.running < self.jobs
+            && !self.load_too_high()
+            && self.acquire_token()
+    }
+
+    /// runs the recipe of a ready target as a job, once a token has been
+    /// taken for it
+    fn launch(&mut self, ready: Ready<'a>) -> io::Result<()> {
+        let Ready { rule, path, deps } = ready;
+        let name = rule.target();
+        self.waiting.remove(name);
+        let lines = match self.expand_recipe(rule, &path, &deps) {
+            Ok(lines) => lines,
+            Err(err) => {
+                self.release_token();
+                return Err(err);
+            }
+        };
+
+        self.status.insert(name.to_owned(), Status::Running);
+        self.running += 1;
+        self.recent_jobs += 1.0;
+        let sender = self.sender.clone();
+        let name = name.to_owned();
+        let output = Output::new(self.merged_output);
+        let sync = self.options.output_sync;
+        thread::spawn(move || {
+            let result = run_job(&name, lines, output, sync);
+            // the run may already have given up on its jobs
+            let _ = sender.send((name, path, result));
+        });
+        Ok(())
+    }
+
+    /// expands every line of a recipe, which is done before any of them are run
+    fn expand_recipe(
+        &self,
+        rule: &FinalRule,
+        path: &str,
+        deps: &[String],
+    ) -> io::Result<Vec<JobLine>> {
+        let mut scope = RecipeScope::new(self.makefile, path, deps);
+        // recipes see the flags passed to sub-makes rather than those inherited
+        let mut var = Variable::simple(self.makeflags.as_str(), Origin::Default);
+        var.set_exported(true);
+        scope.define("MAKEFLAGS", var);
+        let mut expanded = Vec::new();
+        for (i, recipe) in rule.recipes().iter().enumerate() {
+            scope.location = rule.recipe_location(i);
+            let recipe = expand(&mut scope, recipe);
+            expanded.push(recipe.map_err(|err| located(err, scope.location.as_ref()))?);
+        }
+
+        let mut lines = Vec::new();
+        for (i, recipe) in expanded.iter().enumerate() {
+            let mut recipe = recipe.trim();
+            let mut recipe_silent = false;
+            let mut recursive = is_recursive(&rule.recipes()[i]);
+            let mut ignore_errors = self.options.ignore_errors;
+            // prefixes may be given in any order
+            loop {
+                match recipe.chars().next() {
+                    Some('@') => recipe_silent = true,
+                    Some('+') => recursive = true,
+                    Some('-') => ignore_errors = true,
+                    _ => break,
+                }
+                recipe = recipe[1..].trim_start();
+            }
+
+            scope.location = rule.recipe_location(i);
+            let command = shell_command(&mut scope, recipe)
+                .map_err(|err| located(err, scope.location.as_ref()))?;
+            // sub-makes are still run by -n and -t, so that they do the same
+            let (dry_run, touch) = (self.options.dry_run, self.options.touch);
+            let run = recursive || !(dry_run || touch);
+            let echo = if dry_run && !touch {
+                // -n prints every line, even silent ones and those it runs
+                true
+            } else if run {
+                !self.silent && !recipe_silent
+            } else {
+                // -t prints the files it touches in place of the recipe
+                false
+            };
+            lines.push(JobLine {
+                command,
+                echo: echo.then(|| recipe.to_owned()),
+                location: scope.location.clone(),
+                recursive,
+                ignore_errors,
+                run,
+            });
+        }
+        Ok(lines)
+    }
+}
+
+/// describes the failure of a recipe line
+fn job_error(name: &str, location: Option<&Location>, status: i32) -> String {
+    match location {
+        Some((file, line)) => format!("[{}:{}: {}] Error {}", file, line, name, status),
+        None => format!("[{}] Error {}", name, status),
+    }
+}
+
+/// the system's load average over the last minute, if it can be found
+fn load_average() -> Option<f64> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+/// checks whether a recipe line, as written, runs a sub-make
+fn is_recursive(recipe: &str) -> bool {
+    recipe.contains("$(MAKE)") || recipe.contains("${MAKE}")
+}
+
+/// builds the MAKEFLAGS passed to sub-makes, keeping any inherited flags
+/// which this make doesn't handle itself
+fn pass_makeflags(
+    inherited: &str,
+    silent: bool,
+    options: &BuildOptions,
+    jobserver: Option<(usize, &str)>,
+) -> String {
+    // single letter flags are given together, before any others
+    let mut letters = String::new();
+    if options.always_make {
+        letters.push('B');
+    }
+    if options.ignore_errors {
+        letters.push('i');
+    }
+    if options.keep_going {
+        letters.push('k');
+    }
+    if options.dry_run {
+        letters.push('n');
+    }
+    if options.print_data_base {
+        letters.push('p');
+    }
+    if options.question {
+        letters.push('q');
+    }
+    if silent {
+        letters.push('s');
+    }
+    if options.touch {
+        letters.push('t');
+    }
+
+    // a make given its own -j doesn't pass on its parent's jobserver
+    let own_jobs = jobserver.is_some() || options.jobs_given;
+    let mut words = Vec::new();
+    let mut inherited = inherited.split_whitespace().enumerate();
+    for (i, word) in inherited.by_ref() {
+        if word == "--" {
+            break;
+        } else if i == 0 && !word.starts_with('-') {
+            for c in word.chars() {
+                if !MAKEFLAGS_LETTERS.contains(c) && !letters.contains(c) {
+                    letters.push(c);
+                }
+            }
+        } else if !(word.starts_with("-O")
+            || word.starts_with("--output-sync")
+            || word.starts_with("--assume-")
+            || own_jobs && (word.starts_with("-j") || word.starts_with("--jobserver-")))
+        {
+            words.push(word.to_owned());
+        }
+    }
+
+    if let Some((jobs, auth)) = jobserver {
+        words.push(format!("-j{}", jobs));
+        words.push(format!("--jobserver-auth={}", auth));
+    } else if options.jobs.is_none() {
+        words.push(String::from("-j"));
+    }
+    if options.output_sync != OutputSync::None {
+        words.push(format!("-O{}", options.output_sync.name()));
+    }
+    for file in &options.assume_old {
+        words.push(format!("--assume-old={}", file));
+    }
+    for file in &options.assume_new {
+        words.push(format!("--assume-new={}", file));
+    }
+    // variables given on the command line come last
+    let variables: Vec<&str> = inherited.map(|(_, word)| word).collect();
+    if !variables.is_empty() {
+        words.push(String::from("--"));
+        words.extend(variables.into_iter().map(str::to_owned));
+    }
+
+    if !letters.is_empty() {
+        words.insert(0, letters);
+    }
+    words.join(" ")
+}
+
+/// runs the lines of a recipe one after another, stopping at the first to
+/// fail unless its errors are ignored
+fn run_job(
+    name: &str,
+    lines: Vec<JobLine>,
+    mut output: Output,
+    sync: OutputSync,
+) -> Result<(), JobFailure> {
+    for mut line in lines {
+        let capture = sync.captures(line.recursive);
+        if !capture {
+            // anything held back comes before the output of this line
+            output.flush();
+        }
+
+        if let Some(echo) = &line.echo {
+            if capture {
+                output.println(echo);
+            } else {
+                println!("{}", echo);
+            }
+        }
+        if !line.run {
+            continue;
+        }
+
+        let status = if capture {
+            output.run(&mut line.command)
+        } else {
+            line.command.status()
+        };
+        let status = match status {
+            Ok(status) if status.success() => {
+                if sync == OutputSync::Line {
+                    output.flush();
+                }
+                continue;
+            }
+            Ok(status) => status_code(status),
+            Err(err) => {
+                let message = message_at(line.location.as_ref(), &err.to_string());
+                if capture {
+                    output.eprintln(&message);
+                } else {
+                    eprintln!("{}", message);
+                }
+                127
+            }
+        };
+
+        if line.ignore_errors {
+            let message = format!(
+                "{} (ignored)",
+                job_error(name, line.location.as_ref(), status)
+            );
+            let message = message_at(None, &message);
+            if capture {
+                output.eprintln(&message);
+            } else {
+                eprintln!("{}", message);
+            }
+            if sync == OutputSync::Line {
+                output.flush();
+            }
+            continue;
+        }
+        output.flush();
+        return Err((line.location, status));
+    }
+    output.flush();
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+use crate::build::{Run, Status};
+use crate::makefile::{FinalRule, MakeFile};
+use crate::variables::{Flavor, Origin, Variable};
+
+/// prints everything make knows after a run, in the layout used by GNU make's
+/// --print-data-base
+///
+/// lc-make has no implicit rules: targets containing % are ordinary targets,
+/// suffix rules are ignored and double-colon rules are refused when loading,
+/// so every rule is listed with the files and the implicit rules are empty.
+pub(crate) fn print_data_base(
+    makefile: &MakeFile,
+    run: &Run,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(
+        out,
+        "# Make data base, printed on {}",
+        format_time(SystemTime::now())
+    )?;
+
+    writeln!(out)?;
+    writeln!(out, "# Variables")?;
+    writeln!(out)?;
+    let mut names: Vec<&String> = makefile.variables().keys().collect();
+    names.sort();
+    for name in &names {
+        print_variable(out, name, &makefile.variables()[*name])?;
+    }
+    writeln!(out, "# {} variables.", names.len())?;
+
+    writeln!(out)?;
+    writeln!(out, "# Pattern-specific Variable Values")?;
+    writeln!(out)?;
+    writeln!(out, "# No pattern-specific variable values.")?;
+
+    writeln!(out)?;
+    writeln!(out, "# Implicit Rules")?;
+    writeln!(out)?;
+    writeln!(out, "# No implicit rules.")?;
+
+    writeln!(out)?;
+    writeln!(out, "# Files")?;
+    writeln!(out)?;
+    // files which were looked at without having rules come first
+    let mut others: Vec<&String> = run
+        .considered()
+        .filter(|name| makefile.rule(name).is_none())
+        .collect();
+    others.sort();
+    for name in others {
+        writeln!(out, "# Not a target:")?;
+        writeln!(out, "{}:", name)?;
+        print_state(out, makefile, run, name)?;
+        writeln!(out)?;
+    }
+    let files = makefile.rules();
+    for rule in files {
+        print_rule(out, rule)?;
+        print_state(out, makefile, run, rule.target())?;
+        print_recipe(out, rule)?;
+        writeln!(out)?;
+    }
+    writeln!(out, "# {} files.", files.len())?;
+
+    writeln!(out)?;
+    writeln!(out, "# VPATH Search Paths")?;
+    writeln!(out)?;
+    if makefile.vpaths().is_empty() {
+        writeln!(out, "# No 'vpath' search paths.")?;
+    }
+    for (pattern, dirs) in makefile.vpaths() {
+        writeln!(out, "vpath {} {}", pattern, dirs.join(":"))?;
+    }
+    writeln!(out)?;
+    match makefile.variables().get("VPATH") {
+        Some(vpath) if !vpath.value().trim().is_empty() => {
+            writeln!(out, "# General ('VPATH' variable) search path:")?;
+            writeln!(out, "# {}", vpath.value().trim())?;
+        }
+        _ => writeln!(out, "# No general ('VPATH' variable) search path.")?,
+    }
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "# Finished Make data base on {}",
+        format_time(SystemTime::now())
+    )?;
+    writeln!(out)?;
+    out.flush()
+}
+
+/// prints a variable preceded by where it came from
+fn print_variable(out: &mut dyn Write, name: &str, var: &Variable) -> io::Result<()> {
+    match var.origin() {
+        Origin::File => writeln!(out, "# makefile")?,
+        origin => writeln!(out, "# {}", origin)?,
+    }
+    let operator = match var.flavor() {
+        Flavor::Recursive => "=",
+        Flavor::Simple => ":=",
+    };
+    // values spanning lines can only have been given by define
+    if var.value().contains('\n') {
+        writeln!(out, "define {} {}", name, operator)?;
+        writeln!(out, "{}", var.value())?;
+        writeln!(out, "endef")
+    } else {
+        writeln!(out, "{} {} {}", name, operator, var.value())
+    }
+}
+
+/// prints the line introducing a rule, with its prerequisites
+fn print_rule(out: &mut dyn Write, rule: &FinalRule) -> io::Result<()> {
+    if rule.prereqs().is_empty() {
+        writeln!(out, "{}:", rule.target())
+    } else {
+        writeln!(out, "{}: {}", rule.target(), rule.prereqs().join(" "))
+    }
+}
+
+/// prints the recipe of a rule, as it was written
+fn print_recipe(out: &mut dyn Write, rule: &FinalRule) -> io::Result<()> {
+    if rule.recipes().is_empty() {
+        return Ok(());
+    }
+    match rule.location() {
+        Some((file, line)) => writeln!(
+            out,
+            "#  recipe to execute (from '{}', line {}):",
+            file, line
+        )?,
+        None => writeln!(out, "#  recipe to execute:")?,
+    }
+    for recipe in rule.recipes() {
+        writeln!(out, "\t{}", recipe.replace('\n', "\n\t"))?;
+    }
+    Ok(())
+}
+
+/// prints what the run found out about a file
+fn print_state(out: &mut dyn Write, makefile: &MakeFile, run: &Run, name: &str) -> io::Result<()> {
+    let modified = makefile
+        .find_file(name)?
+        .and_then(|path| std::fs::metadata(path).ok())
+        .and_then(|meta| meta.modified().ok());
+    match modified {
+        Some(modified) => writeln!(out, "#  Last modified {}", format_time(modified))?,
+        None => writeln!(out, "#  File does not exist.")?,
+    }
+    match run.status(name) {
+        None => writeln!(out, "#  File has not been updated."),
+        Some(Status::Running) => writeln!(out, "#  Recipe is still being run."),
+        Some(Status::Updated(_, _)) => {
+            writeln!(out, "#  File has been updated.")?;
+            writeln!(out, "#  Successfully updated.")
+        }
+        Some(Status::Failed) => {
+            writeln!(out, "#  File has been updated.")?;
+            writeln!(out, "#  Failed to be updated.")
+        }
+    }
+}
+
+/// formats a time as a UTC date and time, to the nanosecond
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+    // converts days since 1970-01-01 to a date in the proleptic Gregorian calendar
+    let days = days as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:09}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        since_epoch.subsec_nanos()
+    )
+}
@@ -0,0 +1,253 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use crate::build::BuildError;
+use crate::functions;
+use crate::registry::MakeFunction;
+use crate::variables::{Flavor, Origin, Variable};
+
+// define a consistent message to produce on EOF
+pub(crate) const EOF_MESSAGE: &str = "Unexpected EOF";
+
+// the name diagnostics are reported under when they have no location
+const PROGRAM_NAME: &str = "lc-make";
+
+/// A file name and line number within a makefile
+pub(crate) type Location = (String, usize);
+
+/// A set of variables that make text can be expanded against
+pub(crate) trait Scope {
+    /// looks up a variable, returning None if it isn't defined
+    fn lookup(&self, name: &str) -> Option<Variable>;
+
+    /// the names of every variable which is defined
+    fn variable_names(&self) -> Vec<String>;
+
+    /// defines a variable, replacing any existing definition
+    fn define(&mut self, name: &str, var: Variable);
+
+    /// the variables bound during the expansion currently in progress
+    fn bindings(&mut self) -> &mut Bindings;
+
+    /// reads text as if it were part of a makefile
+    fn eval(&mut self, _text: &str) -> io::Result<()> {
+        Err(error("$(eval) can only be used while reading makefiles"))
+    }
+
+    /// looks up a function registered by an embedder
+    fn function(&self, _name: &str) -> Option<Arc<dyn MakeFunction>> {
+        None
+    }
+
+    /// the line of the makefile currently being expanded, if known
+    fn location(&self) -> Option<Location> {
+        None
+    }
+}
+
+/// Variables bound while expanding text, such as the loop variable of
+/// `foreach` or the arguments of `call`, which shadow any other variable
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Bindings {
+    locals: Vec<(String, String)>,
+    expanding: Vec<String>, // Recursive variables currently being expanded
+}
+
+impl Bindings {
+    /// binds a variable until it is released with `truncate`
+    pub(crate) fn push(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.locals.push((name.into(), value.into()));
+    }
+
+    /// releases every binding made after the first len bindings
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.locals.truncate(len);
+    }
+
+    /// the number of variables currently bound
+    pub(crate) fn len(&self) -> usize {
+        self.locals.len()
+    }
+
+    /// checks whether a recursive variable is currently being expanded
+    pub(crate) fn is_expanding(&self, name: &str) -> bool {
+        self.expanding.iter().any(|expanding| expanding == name)
+    }
+
+    /// looks up a bound variable
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.locals
+            .iter()
+            .rev()
+            .find(|(local, _)| local == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// constructs the error produced when expansion fails
+pub(crate) fn error(message: impl Into<String>) -> io::Error {
+    io::Error::other(message.into())
+}
+
+/// An error which has already been attributed to a place in a makefile
+#[derive(Debug)]
+struct LocatedError(String);
+
+impl fmt::Display for LocatedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for LocatedError {}
+
+/// prefixes a diagnostic with the place it came from
+pub(crate) fn message_at(location: Option<&Location>, message: &str) -> String {
+    match location {
+        Some((filename, line)) => format!("{}:{}: {}", filename, line, message),
+        None => format!("{}: {}", PROGRAM_NAME, message),
+    }
+}
+
+/// attributes an error to a line of a makefile, unless it already has been
+pub(crate) fn located(err: io::Error, location: Option<&Location>) -> io::Error {
+    if err
+        .get_ref()
+        .is_some_and(|inner| inner.is::<LocatedError>() || inner.is::<BuildError>())
+    {
+        return err;
+    }
+    let message = message_at(location, &format!("*** {}.  Stop.", err));
+    io::Error::other(LocatedError(message))
+}
+
+/// expands every variable and function reference in a piece of text
+pub(crate) fn expand(scope: &mut dyn Scope, text: &str) -> io::Result<String> {
+    let mut result = String::new();
+    let mut it = text.chars();
+    while let Some(c) = it.next() {
+        match c {
+            '$' => result.push_str(&substitute_var(scope, &mut it)?),
+            x => result.push(x),
+        }
+    }
+    Ok(result)
+}
+
+/// substitutes the reference which follows a `$` for its value
+pub(crate) fn substitute_var(
+    scope: &mut dyn Scope,
+    it: &mut dyn Iterator<Item = char>,
+) -> io::Result<String> {
+    match it.next().ok_or_else(|| error(EOF_MESSAGE))? {
+        '$' => Ok(String::from("$")),
+        // handle bracketed variables
+        '(' => expand_reference(scope, &read_bracketed_var(it, '(', ')')?, '(', ')'),
+        '{' => expand_reference(scope, &read_bracketed_var(it, '{', '}')?, '{', '}'),
+        x => get_var_trimmed(scope, &x.to_string()),
+    }
+}
+
+/// reads the reference which follows a `$` without expanding it,
+/// so that it can be expanded later on
+pub(crate) fn read_reference(it: &mut dyn Iterator<Item = char>) -> io::Result<String> {
+    match it.next().ok_or_else(|| error(EOF_MESSAGE))? {
+        '(' => Ok(format!("({})", read_bracketed_var(it, '(', ')')?)),
+        '{' => Ok(format!("{{{}}}", read_bracketed_var(it, '{', '}')?)),
+        x => Ok(x.to_string()),
+    }
+}
+
+/// function for reading the raw contents of a bracketed variable
+/// nested brackets of the same kind are kept balanced
+pub(crate) fn read_bracketed_var(
+    it: &mut dyn Iterator<Item = char>,
+    open: char,
+    close: char,
+) -> io::Result<String> {
+    let mut variable = String::new();
+    let mut depth = 0;
+
+    loop {
+        match it.next().ok_or_else(|| error(EOF_MESSAGE))? {
+            '#' => return Err(error("Syntax error")),
+            '\\' => match it.next().ok_or_else(|| error(EOF_MESSAGE))? {
+                '\n' => variable.push(' '),
+                x => {
+                    variable.push('\\');
+                    variable.push(x);
+                }
+            },
+            x if x == close && depth == 0 => return Ok(variable),
+            x => {
+                if x == open {
+                    depth += 1;
+                } else if x == close {
+                    depth -= 1;
+                }
+                variable.push(x);
+            }
+        }
+    }
+}
+
+/// expands the contents of a bracketed reference, which is either a
+/// function call, a substitution reference or a plain variable
+pub(crate) fn expand_reference(
+    scope: &mut dyn Scope,
+    reference: &str,
+    open: char,
+    close: char,
+) -> io::Result<String> {
+    if let Some(result) = functions::call_function(scope, reference, open, close) {
+        return result;
+    }
+
+    let name = expand(scope, reference)?;
+
+    // handle substitution references, i.e. $(var:from=to)
+    if let Some(colon) = name.find(':') {
+        if let Some(equals) = name[colon..].find('=') {
+            let value = get_var_trimmed(scope, &name[..colon])?;
+            let from = &name[colon + 1..colon + equals];
+            let to = &name[colon + equals + 1..];
+            return Ok(if from.contains('%') {
+                functions::patsubst(from, to, &value)
+            } else {
+                functions::patsubst(&format!("%{}", from), &format!("%{}", to), &value)
+            });
+        }
+    }
+
+    get_var_trimmed(scope, &name)
+}
+
+/// looks up a variable, including any which are bound during expansion
+pub(crate) fn get_variable(scope: &mut dyn Scope, variable: &str) -> Option<Variable> {
+    match scope.bindings().get(variable) {
+        Some(value) => Some(Variable::simple(value, Origin::Automatic)),
+        None => scope.lookup(variable),
+    }
+}
+
+/// gets a variable from a scope, expanding it if it is recursive, and trims it
+pub(crate) fn get_var_trimmed(scope: &mut dyn Scope, variable: &str) -> io::Result<String> {
+    match get_variable(scope, variable) {
+        Some(var) if var.flavor() == Flavor::Recursive => {
+            if scope.bindings().is_expanding(variable) {
+                return Err(error(format!(
+                    "Recursive variable '{}' references itself (eventually)",
+                    variable
+                )));
+            }
+            scope.bindings().expanding.push(variable.to_owned());
+            let value = expand(scope, var.value());
+            scope.bindings().expanding.pop();
+            Ok(value?.trim().to_owned())
+        }
+        Some(var) => Ok(var.value().trim().to_owned()),
+        None => Ok(String::new()),
+    }
+}
@@ -0,0 +1,1091 @@
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+
+use crate::expand::{error, expand, get_var_trimmed, get_variable, message_at, Scope};
+use crate::registry::FunctionScope;
+use crate::variables::{Flavor, Origin, Variable};
+
+/// The signature shared by every built-in function
+type Function = fn(&mut dyn Scope, Vec<String>) -> io::Result<String>;
+
+/// A function provided by make itself
+struct Builtin {
+    name: &'static str,
+    min_args: usize,
+    max_args: usize, // The last argument keeps any further commas, zero means unlimited
+    expand_args: bool, // Functions which don't expand their arguments do so lazily
+    function: Function,
+}
+
+const BUILTINS: &[Builtin] = &[
+    Builtin::new("subst", 3, 3, true, subst),
+    Builtin::new("patsubst", 3, 3, true, |_, args| {
+        Ok(patsubst(&args[0], &args[1], &args[2]))
+    }),
+    Builtin::new("strip", 0, 1, true, |_, args| Ok(words(&args[0]).join(" "))),
+    Builtin::new("findstring", 2, 2, true, findstring),
+    Builtin::new("filter", 2, 2, true, |_, args| Ok(filter(&args, true))),
+    Builtin::new("filter-out", 2, 2, true, |_, args| Ok(filter(&args, false))),
+    Builtin::new("sort", 0, 1, true, sort),
+    Builtin::new("word", 2, 2, true, word),
+    Builtin::new("words", 0, 1, true, |_, args| {
+        Ok(words(&args[0]).len().to_string())
+    }),
+    Builtin::new("wordlist", 3, 3, true, wordlist),
+    Builtin::new("firstword", 0, 1, true, |_, args| {
+        Ok(words(&args[0])
+            .first()
+            .copied()
+            .unwrap_or_default()
+            .to_owned())
+    }),
+    Builtin::new("lastword", 0, 1, true, |_, args| {
+        Ok(words(&args[0])
+            .last()
+            .copied()
+            .unwrap_or_default()
+            .to_owned())
+    }),
+    Builtin::new("dir", 0, 1, true, |_, args| {
+        Ok(map_words(&args[0], |word| match word.rfind('/') {
+            Some(slash) => Some(word[..=slash].to_owned()),
+            None => Some(String::from("./")),
+        }))
+    }),
+    Builtin::new("notdir", 0, 1, true, |_, args| {
+        Ok(map_words(&args[0], |word| match word.rfind('/') {
+            Some(slash) => Some(word[slash + 1..].to_owned()),
+            None => Some(word.to_owned()),
+        }))
+    }),
+    Builtin::new("suffix", 0, 1, true, |_, args| {
+        Ok(map_words(&args[0], |word| {
+            suffix_start(word).map(|dot| word[dot..].to_owned())
+        }))
+    }),
+    Builtin::new("basename", 0, 1, true, |_, args| {
+        Ok(map_words(&args[0], |word| match suffix_start(word) {
+            Some(dot) => Some(word[..dot].to_owned()),
+            None => Some(word.to_owned()),
+        }))
+    }),
+    Builtin::new("addsuffix", 2, 2, true, |_, args| {
+        Ok(map_words(&args[1], |word| {
+            Some(format!("{}{}", word, args[0]))
+        }))
+    }),
+    Builtin::new("addprefix", 2, 2, true, |_, args| {
+        Ok(map_words(&args[1], |word| {
+            Some(format!("{}{}", args[0], word))
+        }))
+    }),
+    Builtin::new("join", 2, 2, true, join),
+    Builtin::new("wildcard", 0, 1, true, |_, args| {
+        Ok(words(&args[0])
+            .into_iter()
+            .flat_map(wildcard)
+            .collect::<Vec<_>>()
+            .join(" "))
+    }),
+    Builtin::new("realpath", 0, 1, true, |_, args| {
+        Ok(map_words(&args[0], |word| {
+            fs::canonicalize(word)
+                .ok()
+                .and_then(|path| path.into_os_string().into_string().ok())
+        }))
+    }),
+    Builtin::new("abspath", 0, 1, true, |_, args| {
+        Ok(map_words(&args[0], abspath))
+    }),
+    Builtin::new("if", 2, 3, false, if_function),
+    Builtin::new("or", 1, 0, false, or),
+    Builtin::new("and", 1, 0, false, and),
+    Builtin::new("foreach", 3, 3, false, foreach),
+    Builtin::new("call", 1, 0, true, call),
+    Builtin::new("let", 3, 3, false, let_function),
+    Builtin::new("origin", 0, 1, true, |scope, args| {
+        Ok(get_variable(scope, args[0].trim())
+            .map(|var| var.origin().to_string())
+            .unwrap_or_else(|| String::from("undefined")))
+    }),
+    Builtin::new("flavor", 0, 1, true, |scope, args| {
+        Ok(get_variable(scope, args[0].trim())
+            .map(|var| var.flavor().to_string())
+            .unwrap_or_else(|| String::from("undefined")))
+    }),
+    Builtin::new("value", 0, 1, true, |scope, args| {
+        Ok(get_variable(scope, args[0].trim())
+            .map(|var| var.value().to_owned())
+            .unwrap_or_default())
+    }),
+    Builtin::new("eval", 0, 1, true, |scope, args| {
+        scope.eval(&args[0])?;
+        Ok(String::new())
+    }),
+    Builtin::new("error", 0, 1, true, |_, args| Err(error(args[0].clone()))),
+    Builtin::new("warning", 0, 1, true, |scope, args| {
+        eprintln!("{}", message_at(scope.location().as_ref(), &args[0]));
+        Ok(String::new())
+    }),
+    Builtin::new("info", 0, 1, true, |_, args| {
+        println!("{}", args[0]);
+        Ok(String::new())
+    }),
+    Builtin::new("file", 1, 2, true, file),
+    Builtin::new("shell", 0, 1, true, shell),
+];
+
+impl Builtin {
+    const fn new(
+        name: &'static str,
+        min_args: usize,
+        max_args: usize,
+        expand_args: bool,
+        function: Function,
+    ) -> Self {
+        Self {
+            name,
+            min_args,
+            max_args,
+            expand_args,
+            function,
+        }
+    }
+}
+
+/// checks whether a function is provided by make itself
+pub(crate) fn is_builtin(name: &str) -> bool {
+    BUILTINS.iter().any(|b| b.name == name)
+}
+
+/// calls the built-in or registered function named at the start of a
+/// bracketed reference, returning None if the reference isn't a function call
+pub(crate) fn call_function(
+    scope: &mut dyn Scope,
+    reference: &str,
+    open: char,
+    close: char,
+) -> Option<io::Result<String>> {
+    // built-in function names are always followed by whitespace, whereas
+    // registered functions may also be called without any arguments
+    let (name, rest) = match reference.find([' ', '\t']) {
+        Some(end) => (&reference[..end], Some(reference[end..].trim_start())),
+        None => (reference, None),
+    };
+    let builtin = rest.and_then(|_| BUILTINS.iter().find(|b| b.name == name));
+    let registered = match builtin {
+        Some(_) => None,
+        None => Some(scope.function(name)?),
+    };
+    let (min_args, max_args, expand_args) = match (builtin, &registered) {
+        (Some(b), _) => (b.min_args, b.max_args, b.expand_args),
+        (_, Some(f)) => (f.min_args(), f.max_args(), f.expand_args()),
+        _ => return None,
+    };
+
+    let args = match rest {
+        Some(rest) => split_args(rest, open, close, max_args),
+        None => Vec::new(),
+    };
+    if args.len() < min_args {
+        return Some(Err(error(format!(
+            "insufficient number of arguments ({}) to function '{}'",
+            args.len(),
+            name
+        ))));
+    }
+
+    let args = if expand_args {
+        match args.iter().map(|arg| expand(scope, arg)).collect() {
+            Ok(args) => args,
+            Err(err) => return Some(Err(err)),
+        }
+    } else {
+        args
+    };
+    match (builtin, registered) {
+        (Some(b), _) => Some((b.function)(scope, args)),
+        (_, Some(f)) => Some(f.call(&mut FunctionScope::new(scope), &args)),
+        _ => None,
+    }
+}
+
+/// splits the arguments of a function call on commas outside of brackets
+fn split_args(args: &str, open: char, close: char, max_args: usize) -> Vec<String> {
+    let mut result = vec![String::new()];
+    let mut depth = 0;
+    for c in args.chars() {
+        if c == ',' && depth == 0 && result.len() != max_args {
+            result.push(String::new());
+            continue;
+        } else if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+        }
+        result.last_mut().unwrap().push(c);
+    }
+    result
+}
+
+/// splits text into whitespace separated words
+pub(crate) fn words(text: &str) -> Vec<&str> {
+    text.split_whitespace().collect()
+}
+
+/// splits a pattern around its first `%`, returning None if it has none
+/// a `%` can be escaped with a backslash to match it literally
+pub(crate) fn split_pattern(pattern: &str) -> Option<(String, String)> {
+    let mut prefix = String::new();
+    let mut it = pattern.chars().peekable();
+    while let Some(c) = it.next() {
+        match c {
+            '\\' if it.peek() == Some(&'%') => {
+                it.next();
+                prefix.push('%');
+            }
+            '%' => return Some((prefix, it.collect())),
+            x => prefix.push(x),
+        }
+    }
+    None
+}
+
+/// matches a word against a pattern's prefix and suffix, returning the stem
+pub(crate) fn match_pattern<'a>(prefix: &str, suffix: &str, word: &'a str) -> Option<&'a str> {
+    if word.len() >= prefix.len() + suffix.len()
+        && word.starts_with(prefix)
+        && word.ends_with(suffix)
+    {
+        Some(&word[prefix.len()..word.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+/// checks whether a word matches a pattern which may contain a `%`
+pub(crate) fn matches(pattern: &str, word: &str) -> bool {
+    match split_pattern(pattern) {
+        Some((prefix, suffix)) => match_pattern(&prefix, &suffix, word).is_some(),
+        None => pattern.replace("\\%", "%") == word,
+    }
+}
+
+/// replaces every word of text matching pattern with replacement,
+/// where a `%` in replacement stands for the text matched by the `%` in pattern
+pub(crate) fn patsubst(pattern: &str, replacement: &str, text: &str) -> String {
+    let pattern_parts = split_pattern(pattern);
+    let replacement_parts = split_pattern(replacement);
+    let replace = |stem: &str| match &replacement_parts {
+        Some((prefix, suffix)) => format!("{}{}{}", prefix, stem, suffix),
+        None => replacement.replace("\\%", "%"),
+    };
+
+    words(text)
+        .into_iter()
+        .map(|word| match &pattern_parts {
+            Some((prefix, suffix)) => match_pattern(prefix, suffix, word)
+                .map(replace)
+                .unwrap_or_else(|| word.to_owned()),
+            None if pattern.replace("\\%", "%") == word => replacement.replace("\\%", "%"),
+            None => word.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn subst(_: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    if args[0].is_empty() {
+        // GNU make appends the replacement when asked to substitute nothing
+        Ok(args[2].clone() + &args[1])
+    } else {
+        Ok(args[2].replace(&args[0], &args[1]))
+    }
+}
+
+fn findstring(_: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    if args[1].contains(&args[0]) {
+        Ok(args[0].clone())
+    } else {
+        Ok(String::new())
+    }
+}
+
+/// keeps the words which match (or don't match) any of the patterns
+fn filter(args: &[String], keep: bool) -> String {
+    let patterns = words(&args[0]);
+    words(&args[1])
+        .into_iter()
+        .filter(|word| patterns.iter().any(|pattern| matches(pattern, word)) == keep)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn sort(_: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    let mut list = words(&args[0]);
+    list.sort_unstable();
+    list.dedup();
+    Ok(list.join(" "))
+}
+
+/// parses the numeric argument of a function
+fn parse_number(arg: &str, position: &str, function: &str) -> io::Result<i64> {
+    arg.trim().parse().map_err(|_| {
+        error(format!(
+            "non-numeric {} argument to '{}' function: '{}'",
+            position,
+            function,
+            arg.trim()
+        ))
+    })
+}
+
+fn word(_: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    let n = parse_number(&args[0], "first", "word")?;
+    if n < 1 {
+        return Err(error(
+            "first argument to 'word' function must be greater than 0",
+        ));
+    }
+    Ok(words(&args[1])
+        .get(n as usize - 1)
+        .copied()
+        .unwrap_or_default()
+        .to_owned())
+}
+
+fn wordlist(_: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    let start = parse_number(&args[0], "first", "wordlist")?;
+    let end = parse_number(&args[1], "second", "wordlist")?;
+    if start < 1 {
+        return Err(error(format!(
+            "invalid first argument to 'wordlist' function: '{}'",
+            start
+        )));
+    }
+    if end < 0 {
+        return Err(error(format!(
+            "invalid second argument to 'wordlist' function: '{}'",
+            end
+        )));
+    }
+    Ok(words(&args[2])
+        .into_iter()
+        .skip(start as usize - 1)
+        .take((end - start + 1).max(0) as usize)
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// applies a function to every word of text, dropping the words it returns None for
+fn map_words(text: &str, f: impl Fn(&str) -> Option<String>) -> String {
+    words(text)
+        .into_iter()
+        .filter_map(f)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// finds the `.` starting the suffix of the last component of a file name
+fn suffix_start(word: &str) -> Option<usize> {
+    let dot = word.rfind('.')?;
+    match word.rfind('/') {
+        Some(slash) if slash > dot => None,
+        _ => Some(dot),
+    }
+}
+
+fn join(_: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    let first = words(&args[0]);
+    let second = words(&args[1]);
+    Ok((0..first.len().max(second.len()))
+        .map(|i| {
+            format!(
+                "{}{}",
+                first.get(i).copied().unwrap_or_default(),
+                second.get(i).copied().unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// makes a file name absolute, removing any `.` and `..` components
+/// without resolving symbolic links
+pub(crate) fn abspath(name: &str) -> Option<String> {
+    let mut path = if name.starts_with('/') {
+        PathBuf::from("/")
+    } else {
+        env::current_dir().ok()?
+    };
+    for component in name.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                path.pop();
+            }
+            x => path.push(x),
+        }
+    }
+    path.into_os_string().into_string().ok()
+}
+
+/// checks whether text contains any glob characters
+fn is_glob(text: &str) -> bool {
+    text.contains(['*', '?', '['])
+}
+
+/// expands a glob pattern into the sorted list of existing files matching it
+pub(crate) fn wildcard(pattern: &str) -> Vec<String> {
+    let pattern = match pattern.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            env::var("HOME").unwrap_or_default() + rest
+        }
+        _ => pattern.to_owned(),
+    };
+
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec![String::from("/")], rest),
+        None => (vec![String::new()], pattern.as_str()),
+    };
+
+    let components: Vec<&str> = rest.split('/').collect();
+    for (i, component) in components.iter().enumerate() {
+        let separator = if i + 1 < components.len() { "/" } else { "" };
+        paths = if is_glob(component) {
+            paths
+                .into_iter()
+                .flat_map(|base| {
+                    let dir = if base.is_empty() { "." } else { base.as_str() };
+                    fs::read_dir(dir)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                        // hidden files are only matched explicitly
+                        .filter(|name| !name.starts_with('.') || component.starts_with('.'))
+                        .filter(|name| glob_match(component, name))
+                        .map(|name| format!("{}{}{}", base, name, separator))
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        } else {
+            paths
+                .into_iter()
+                .map(|base| format!("{}{}{}", base, component, separator))
+                .collect()
+        };
+    }
+
+    paths.retain(|path| fs::symlink_metadata(Path::new(path)).is_ok());
+    paths.sort();
+    paths
+}
+
+/// matches a file name against a glob pattern made of `*`, `?` and `[...]`
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    glob_match_chars(&pattern, &name)
+}
+
+fn glob_match_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|i| glob_match_chars(&pattern[1..], &name[i..])),
+        Some('?') => !name.is_empty() && glob_match_chars(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let c = match name.first() {
+                Some(c) => *c,
+                None => return false,
+            };
+            let mut i = 1;
+            let negate = matches!(pattern.get(i), Some('!') | Some('^'));
+            if negate {
+                i += 1;
+            }
+            let mut found = false;
+            let mut first = true;
+            while i < pattern.len() && (first || pattern[i] != ']') {
+                first = false;
+                if pattern.get(i + 1) == Some(&'-')
+                    && i + 2 < pattern.len()
+                    && pattern[i + 2] != ']'
+                {
+                    found |= pattern[i] <= c && c <= pattern[i + 2];
+                    i += 3;
+                } else {
+                    found |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+            if i >= pattern.len() {
+                // an unterminated bracket matches itself literally
+                return c == '[' && glob_match_chars(&pattern[1..], &name[1..]);
+            }
+            found != negate && glob_match_chars(&pattern[i + 1..], &name[1..])
+        }
+        Some('\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1]) && glob_match_chars(&pattern[2..], &name[1..])
+        }
+        Some(x) => name.first() == Some(x) && glob_match_chars(&pattern[1..], &name[1..]),
+    }
+}
+
+/// expands a condition, stripping any surrounding whitespace
+fn condition(scope: &mut dyn Scope, arg: &str) -> io::Result<String> {
+    Ok(expand(scope, arg.trim())?.trim().to_owned())
+}
+
+fn if_function(scope: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    // only the branch which is taken gets expanded
+    if !condition(scope, &args[0])?.is_empty() {
+        expand(scope, &args[1])
+    } else if let Some(otherwise) = args.get(2) {
+        expand(scope, otherwise)
+    } else {
+        Ok(String::new())
+    }
+}
+
+fn or(scope: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    for arg in &args {
+        let value = condition(scope, arg)?;
+        if !value.is_empty() {
+            return Ok(value);
+        }
+    }
+    Ok(String::new())
+}
+
+fn and(scope: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    let mut value = String::new();
+    for arg in &args {
+        value = condition(scope, arg)?;
+        if value.is_empty() {
+            break;
+        }
+    }
+    Ok(value)
+}
+
+/// runs an expansion with variables bound, releasing them again afterwards
+fn with_bindings(
+    scope: &mut dyn Scope,
+    bindings: Vec<(String, String)>,
+    f: impl FnOnce(&mut dyn Scope) -> io::Result<String>,
+) -> io::Result<String> {
+    let len = scope.bindings().len();
+    for (name, value) in bindings {
+        scope.bindings().push(name, value);
+    }
+    let result = f(scope);
+    scope.bindings().truncate(len);
+    result
+}
+
+fn foreach(scope: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    let name = expand(scope, &args[0])?.trim().to_owned();
+    let list = expand(scope, &args[1])?;
+
+    let mut result = Vec::new();
+    for word in words(&list) {
+        let binding = vec![(name.clone(), word.to_owned())];
+        result.push(with_bindings(scope, binding, |scope| {
+            expand(scope, &args[2])
+        })?);
+    }
+    Ok(result.join(" "))
+}
+
+fn call(scope: &mut dyn Scope, mut args: Vec<String>) -> io::Result<String> {
+    let name = args[0].trim().to_owned();
+
+    // calling a built-in function passes the arguments straight through
+    if let Some(builtin) = BUILTINS.iter().find(|b| b.name == name) {
+        args.remove(0);
+        // built-in functions always have their first argument, even if empty
+        if args.is_empty() {
+            args.push(String::new());
+        }
+        // the arguments have already been expanded, so those of functions
+        // which expand their own are escaped to expand to themselves again
+        if !builtin.expand_args {
+            args = args.iter().map(|arg| arg.replace('$', "$$")).collect();
+        }
+        if args.len() < builtin.min_args {
+            return Err(error(format!(
+                "insufficient number of arguments ({}) to function '{}'",
+                args.len(),
+                builtin.name
+            )));
+        }
+        return (builtin.function)(scope, args);
+    }
+
+    // $(0) is the name of the variable and $(1) onwards are the arguments,
+    // hiding any arguments left over from an enclosing call
+    let mut bindings: Vec<(String, String)> = args
+        .into_iter()
+        .enumerate()
+        .map(|(i, arg)| (i.to_string(), if i == 0 { name.clone() } else { arg }))
+        .collect();
+    let mut i = bindings.len();
+    while scope.bindings().get(&i.to_string()).is_some() {
+        bindings.push((i.to_string(), String::new()));
+        i += 1;
+    }
+
+    // a variable may call itself, so unlike a reference it isn't stopped
+    // from expanding itself again
+    with_bindings(scope, bindings, |scope| match get_variable(scope, &name) {
+        Some(var) if var.flavor() == Flavor::Recursive => {
+            Ok(expand(scope, var.value())?.trim().to_owned())
+        }
+        Some(var) => Ok(var.value().trim().to_owned()),
+        None => Ok(String::new()),
+    })
+}
+
+fn let_function(scope: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    let names = expand(scope, &args[0])?;
+    let list = expand(scope, &args[1])?;
+    let names = words(&names);
+    let mut values = words(&list).into_iter();
+
+    // the last variable takes every remaining word
+    let mut bindings = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        let value = if i + 1 == names.len() {
+            values.by_ref().collect::<Vec<_>>().join(" ")
+        } else {
+            values.next().unwrap_or_default().to_owned()
+        };
+        bindings.push((name.to_string(), value));
+    }
+    with_bindings(scope, bindings, |scope| expand(scope, &args[2]))
+}
+
+fn file(_: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    let spec = args[0].trim();
+    let (op, name) = if let Some(name) = spec.strip_prefix(">>") {
+        (">>", name.trim())
+    } else if let Some(name) = spec.strip_prefix('>') {
+        (">", name.trim())
+    } else if let Some(name) = spec.strip_prefix('<') {
+        ("<", name.trim())
+    } else {
+        return Err(error(format!("invalid file operation: {}", spec)));
+    };
+    if name.is_empty() {
+        return Err(error("file: missing filename"));
+    }
+
+    if op == "<" {
+        if args.len() > 1 {
+            return Err(error("file: too many arguments"));
+        }
+        // a missing file reads as empty, and one trailing newline is dropped
+        return match fs::read(name) {
+            Ok(content) => {
+                let content = String::from_utf8_lossy(&content);
+                Ok(content.strip_suffix('\n').unwrap_or(&content).to_owned())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(err) => Err(error(format!("open: {}: {}", name, err))),
+        };
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(op == ">>")
+        .truncate(op == ">")
+        .open(name)
+        .map_err(|err| error(format!("open: {}: {}", name, err)))?;
+    if let Some(text) = args.get(1) {
+        let mut text = text.clone();
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        file.write_all(text.as_bytes())
+            .map_err(|err| error(format!("write: {}: {}", name, err)))?;
+    }
+    Ok(String::new())
+}
+
+/// builds a command which runs a line of text under the configured SHELL,
+/// with every exported variable placed in its environment
+pub(crate) fn shell_command(scope: &mut dyn Scope, line: &str) -> io::Result<Command> {
+    let shell = get_var_trimmed(scope, "SHELL")?;
+    let flags = get_var_trimmed(scope, ".SHELLFLAGS")?;
+    let mut command = Command::new(if shell.is_empty() { "/bin/sh" } else { &shell });
+    command.args(words(&flags)).arg(line);
+
+    for name in scope.variable_names() {
+        let var = match scope.lookup(&name) {
+            Some(var) => var,
+            None => continue,
+        };
+        if !var.exported() {
+            if var.origin() == Origin::Environment {
+                command.env_remove(&name);
+            }
+        } else if var.flavor() == Flavor::Simple || var.origin() == Origin::Environment {
+            command.env(&name, var.value());
+        } else if !scope.bindings().is_expanding(&name) {
+            // a variable whose expansion runs a shell can't export itself to it
+            let value = get_var_trimmed(scope, &name)?;
+            command.env(&name, value);
+        }
+    }
+    Ok(command)
+}
+
+/// the exit status of a process, as a shell would report it
+pub(crate) fn status_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+fn shell(scope: &mut dyn Scope, args: Vec<String>) -> io::Result<String> {
+    let output = shell_command(scope, &args[0])?
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output();
+
+    let (result, status) = match output {
+        Ok(output) => {
+            // newlines become spaces, apart from trailing ones which are dropped
+            let stdout = String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n");
+            let stdout = stdout.trim_end_matches('\n');
+            (stdout.replace('\n', " "), status_code(output.status))
+        }
+        Err(err) => {
+            let message = format!("{}: {}", args[0].trim(), err);
+            eprintln!("{}", message_at(scope.location().as_ref(), &message));
+            (String::new(), 127)
+        }
+    };
+
+    scope.define(
+        ".SHELLSTATUS",
+        Variable::simple(status.to_string(), Origin::Override),
+    );
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expand::Bindings;
+    use std::collections::HashMap;
+
+    /// A scope holding nothing but the variables given to it
+    #[derive(Default)]
+    struct TestScope {
+        variables: HashMap<String, Variable>,
+        bindings: Bindings,
+    }
+
+    impl Scope for TestScope {
+        fn lookup(&self, name: &str) -> Option<Variable> {
+            self.variables.get(name).cloned()
+        }
+
+        fn variable_names(&self) -> Vec<String> {
+            self.variables.keys().cloned().collect()
+        }
+
+        fn define(&mut self, name: &str, var: Variable) {
+            self.variables.insert(name.to_owned(), var);
+        }
+
+        fn bindings(&mut self) -> &mut Bindings {
+            &mut self.bindings
+        }
+    }
+
+    /// expands text against a scope holding the given recursive variables
+    fn expand_with(variables: &[(&str, &str)], text: &str) -> io::Result<String> {
+        let mut scope = TestScope::default();
+        for (name, value) in variables {
+            let var = Variable::new(value.to_string(), Flavor::Recursive, Origin::File);
+            scope.variables.insert(name.to_string(), var);
+        }
+        expand(&mut scope, text)
+    }
+
+    #[test]
+    fn split_args_on_top_level_commas() {
+        assert_eq!(split_args("a,b,c", '(', ')', 0), ["a", "b", "c"]);
+        assert_eq!(split_args("$(f a,b),c", '(', ')', 0), ["$(f a,b)", "c"]);
+        assert_eq!(split_args("${f a,b},c", '{', '}', 0), ["${f a,b}", "c"]);
+        assert_eq!(split_args(",", '(', ')', 0), ["", ""]);
+    }
+
+    #[test]
+    fn split_args_keeps_commas_in_last_arg() {
+        assert_eq!(split_args("a,b,c", '(', ')', 2), ["a", "b,c"]);
+        assert_eq!(split_args("a,b", '(', ')', 1), ["a,b"]);
+    }
+
+    #[test]
+    fn patsubst_replaces_matching_words() {
+        assert_eq!(patsubst("%.c", "%.o", "a.c b.h  c.c"), "a.o b.h c.o");
+        assert_eq!(patsubst("src/%", "%", "src/a src/b/c"), "a b/c");
+        assert_eq!(patsubst("%", "x%y", "a b"), "xay xby");
+    }
+
+    #[test]
+    fn patsubst_without_percent_matches_whole_words() {
+        assert_eq!(patsubst("a.c", "b.c", "a.c aa.c"), "b.c aa.c");
+        assert_eq!(patsubst("\\%.c", "%.o", "%.c a.c"), "%.o a.c");
+    }
+
+    #[test]
+    fn text_functions() {
+        let variables = [("list", "b a  c a")];
+        let cases = [
+            ("$(subst a,x,banana)", "bxnxnx"),
+            ("$(patsubst %.c,%.o,a.c b.h)", "a.o b.h"),
+            ("[$(strip  a   b )]", "[a b]"),
+            ("$(findstring an,banana)|$(findstring x,banana)", "an|"),
+            ("$(filter %.c %.h,a.c b.o c.h)", "a.c c.h"),
+            ("$(filter-out %.c,a.c b.o)", "b.o"),
+            ("$(sort $(list))", "a b c"),
+            ("$(word 2,$(list))|$(word 9,$(list))", "a|"),
+            ("$(words $(list))", "4"),
+            ("$(wordlist 2,3,$(list))", "a c"),
+            ("$(firstword $(list))|$(lastword $(list))", "b|a"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(expand_with(&variables, text).unwrap(), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn function_argument_errors() {
+        let err = expand_with(&[], "$(subst a,b)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "insufficient number of arguments (2) to function 'subst'"
+        );
+        assert!(expand_with(&[], "$(word 0,a b)").is_err());
+        assert!(expand_with(&[], "$(wordlist x,2,a b)").is_err());
+    }
+
+    #[test]
+    fn references_which_are_not_functions_are_variables() {
+        let variables = [("subst", "value")];
+        assert_eq!(expand_with(&variables, "$(subst)").unwrap(), "value");
+    }
+
+    #[test]
+    fn filename_functions() {
+        let cases = [
+            ("$(dir src/a.c b.c /x/)", "src/ ./ /x/"),
+            ("$(notdir src/a.c b.c /x/)", "a.c b.c "),
+            ("$(suffix src/a.c b.d/c x)", ".c"),
+            ("$(basename src/a.c b.d/c x.y.z)", "src/a b.d/c x.y"),
+            ("$(addsuffix .c,a b)", "a.c b.c"),
+            ("$(addprefix src/,a b)", "src/a src/b"),
+            ("$(join a b c,.c .h)", "a.c b.h c"),
+            ("$(abspath /a/./b/../c //d)", "/a/c /d"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(expand_with(&[], text).unwrap(), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("*.c", "main.c"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.c", "main.h"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(glob_match("\\*", "*"));
+        assert!(!glob_match("\\*", "a"));
+    }
+
+    #[test]
+    fn glob_match_brackets() {
+        assert!(glob_match("[abc]x", "bx"));
+        assert!(glob_match("[a-c]x", "cx"));
+        assert!(!glob_match("[a-c]x", "dx"));
+        assert!(glob_match("[!a-c]x", "dx"));
+        assert!(glob_match("[^a]", "b"));
+        assert!(glob_match("[]]", "]"));
+        assert!(glob_match("[ab", "[ab"));
+    }
+
+    #[test]
+    fn wildcard_lists_matching_files() {
+        let dir = std::env::temp_dir().join(format!("lc-make-wildcard-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["b.c", "a.c", "c.h", ".hidden.c", "sub/d.c"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let base = dir.display().to_string();
+
+        let found = wildcard(&format!("{}/*.c", base));
+        assert_eq!(found, [format!("{}/a.c", base), format!("{}/b.c", base)]);
+        assert_eq!(
+            wildcard(&format!("{}/.*.c", base)),
+            [format!("{}/.hidden.c", base)]
+        );
+        assert_eq!(
+            wildcard(&format!("{}/*/?.c", base)),
+            [format!("{}/sub/d.c", base)]
+        );
+        assert_eq!(
+            wildcard(&format!("{}/c.h", base)),
+            [format!("{}/c.h", base)]
+        );
+        assert!(wildcard(&format!("{}/missing", base)).is_empty());
+        let text = format!("$(wildcard {0}/*.h {0}/sub/*)", base);
+        let expected = format!("{0}/c.h {0}/sub/d.c", base);
+        assert_eq!(expand_with(&[], &text).unwrap(), expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn conditional_functions() {
+        let variables = [("yes", "y"), ("no", "")];
+        let cases = [
+            ("$(if $(yes),a,b)|$(if $(no),a,b)|$(if $(no),a)", "a|b|"),
+            ("$(or $(no),,x,$(error unreached))", "x"),
+            ("[$(or $(no),)]", "[]"),
+            ("$(and a,$(yes),z)", "z"),
+            ("[$(and a,$(no),$(error unreached))]", "[]"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(expand_with(&variables, text).unwrap(), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn foreach_binds_each_word() {
+        let variables = [("list", "a b c"), ("w", "outer")];
+        let text = "$(foreach w,$(list),<$(w)>) $(w)";
+        assert_eq!(expand_with(&variables, text).unwrap(), "<a> <b> <c> outer");
+    }
+
+    #[test]
+    fn call_binds_arguments() {
+        let variables = [("pair", "$(0):$(2),$(1)")];
+        assert_eq!(
+            expand_with(&variables, "$(call pair,a,b)").unwrap(),
+            "pair:b,a"
+        );
+        assert_eq!(
+            expand_with(&variables, "$(call pair,a)").unwrap(),
+            "pair:,a"
+        );
+        assert_eq!(expand_with(&[], "$(call undefined,a)").unwrap(), "");
+    }
+
+    #[test]
+    fn call_recurses() {
+        let variables = [(
+            "reverse",
+            "$(if $(wordlist 2,2,$(1)),$(call reverse,$(wordlist 2,$(words $(1)),$(1))) $(firstword $(1)),$(1))",
+        )];
+        let result = expand_with(&variables, "[$(call reverse,a b c d)]").unwrap();
+        assert_eq!(result, "[d c b a]");
+    }
+
+    #[test]
+    fn call_hides_arguments_of_enclosing_calls() {
+        let variables = [("outer", "$(call inner,x)"), ("inner", "$(1)$(2)")];
+        assert_eq!(expand_with(&variables, "$(call outer,a,b)").unwrap(), "x");
+    }
+
+    #[test]
+    fn call_passes_arguments_to_builtins() {
+        assert_eq!(expand_with(&[], "$(call strip,  a   b )").unwrap(), "a b");
+        assert_eq!(expand_with(&[], "$(call subst,a,b,aaa)").unwrap(), "bbb");
+        assert_eq!(expand_with(&[], "[$(call strip)]").unwrap(), "[]");
+        assert_eq!(expand_with(&[], "[$(call sort)]").unwrap(), "[]");
+        assert_eq!(expand_with(&[], "[$(call firstword)]").unwrap(), "[]");
+        assert!(expand_with(&[], "$(call subst,a)").is_err());
+    }
+
+    #[test]
+    fn call_expands_arguments_of_lazy_builtins_once() {
+        let variables = [("x", "expanded twice")];
+        let text = "$(call if,1,$$(x),no)";
+        assert_eq!(expand_with(&variables, text).unwrap(), "$(x)");
+        let text = "$(call foreach,w,a b,$$(w))";
+        assert_eq!(expand_with(&variables, text).unwrap(), "$(w) $(w)");
+    }
+
+    #[test]
+    fn let_binds_words() {
+        let text = "$(let a b,1 2 3,$(a)|$(b))";
+        assert_eq!(expand_with(&[], text).unwrap(), "1|2 3");
+        let text = "$(let a b c,1,[$(a)|$(b)|$(c)])";
+        assert_eq!(expand_with(&[], text).unwrap(), "[1||]");
+    }
+
+    #[test]
+    fn let_shadows_variables_only_within_its_body() {
+        let variables = [("a", "outer")];
+        let result = expand_with(&variables, "$(let a,inner,$(a)) $(a)").unwrap();
+        assert_eq!(result, "inner outer");
+    }
+
+    #[test]
+    fn recursive_variables_expand_when_referenced() {
+        let variables = [("a", "$(b)!"), ("b", "value"), ("loop", "x$(loop)")];
+        assert_eq!(expand_with(&variables, "$(a)").unwrap(), "value!");
+        let err = expand_with(&variables, "$(loop)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Recursive variable 'loop' references itself (eventually)"
+        );
+    }
+
+    #[test]
+    fn file_writes_appends_and_reads() {
+        let dir = std::env::temp_dir().join(format!("lc-make-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("out").display().to_string();
+
+        let text = format!("$(file >{0},one)$(file >>{0},two\n)$(file >>{0})", name);
+        assert_eq!(expand_with(&[], &text).unwrap(), "");
+        assert_eq!(std::fs::read_to_string(&name).unwrap(), "one\ntwo\n");
+        // only the final newline is dropped when reading
+        let read = expand_with(&[], &format!("$(file <{})", name)).unwrap();
+        assert_eq!(read, "one\ntwo");
+        let text = format!("$(file > {},replaced)$(file < {})", name, name);
+        assert_eq!(expand_with(&[], &text).unwrap(), "replaced");
+        let missing = format!("$(file <{}/missing)", dir.display());
+        assert_eq!(expand_with(&[], &missing).unwrap(), "");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_argument_errors() {
+        let cases = [
+            ("$(file out)", "invalid file operation: out"),
+            ("$(file >)", "file: missing filename"),
+            ("$(file <in,text)", "file: too many arguments"),
+        ];
+        for (text, expected) in cases {
+            let err = expand_with(&[], text).unwrap_err();
+            assert_eq!(err.to_string(), expected, "{}", text);
+        }
+    }
+}
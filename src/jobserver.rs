@@ -0,0 +1,188 @@
+#[cfg(not(unix))]
+pub(crate) use self::fallback::Jobserver;
+#[cfg(unix)]
+pub(crate) use self::unix::Jobserver;
+
+/// How the jobserver's tokens are shared with sub-makes
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum JobserverStyle {
+    Fifo, // A named pipe, which is found through its path
+    Pipe, // An anonymous pipe, whose descriptors are inherited
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::env;
+    use std::ffi::CString;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, ErrorKind, PipeReader, PipeWriter, Read, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::JobserverStyle;
+
+    /// The GNU make jobserver, a pipe holding one token for every job which may
+    /// run on top of the one each make is always allowed, shared by every make
+    /// in a process tree so that together they stay within the limit
+    pub(crate) struct Jobserver {
+        read: File, // Opened without blocking, so that tokens can be polled for
+        write: File,
+        auth: String,          // The value of --jobserver-auth given to sub-makes
+        fifo: Option<PathBuf>, // A fifo created by this make, removed when it's done
+        _pipe: Option<(PipeReader, PipeWriter)>, // A pipe created by this make, kept open for sub-makes
+    }
+
+    impl Jobserver {
+        /// creates a jobserver allowing the given number of jobs at once
+        pub(crate) fn create(jobs: usize, style: JobserverStyle) -> io::Result<Self> {
+            let mut jobserver = match style {
+                JobserverStyle::Fifo => {
+                    let path = env::temp_dir().join(format!("GMfifo{}", process::id()));
+                    let c_path = CString::new(path.as_os_str().as_bytes())?;
+                    // the fifo may be left over from a make with the same pid
+                    let _ = fs::remove_file(&path);
+                    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    let auth = format!("fifo:{}", path.display());
+                    let path_name = path.display().to_string();
+                    let mut jobserver = Self::open(&path_name, &path_name, auth)?;
+                    jobserver.fifo = Some(path);
+                    jobserver
+                }
+                JobserverStyle::Pipe => {
+                    let (reader, writer) = io::pipe()?;
+                    // sub-makes inherit the pipe, so it mustn't close when they're run
+                    for fd in [reader.as_raw_fd(), writer.as_raw_fd()] {
+                        if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    let (r, w) = (reader.as_raw_fd(), writer.as_raw_fd());
+                    let auth = format!("{},{}", r, w);
+                    let mut jobserver =
+                        Self::open(&format!("/dev/fd/{}", r), &format!("/dev/fd/{}", w), auth)?;
+                    jobserver._pipe = Some((reader, writer));
+                    jobserver
+                }
+            };
+
+            jobserver
+                .write
+                .write_all(&b"+".repeat(jobs.saturating_sub(1)))?;
+            Ok(jobserver)
+        }
+
+        /// joins the jobserver of a parent make, as advertised in MAKEFLAGS,
+        /// returning None if it isn't using one
+        pub(crate) fn join(makeflags: &str) -> Option<io::Result<Self>> {
+            let auth = makeflags
+                .split_whitespace()
+                .filter_map(|flag| {
+                    flag.strip_prefix("--jobserver-auth=")
+                        .or_else(|| flag.strip_prefix("--jobserver-fds="))
+                })
+                .next_back()?;
+
+            Some(match auth.strip_prefix("fifo:") {
+                Some(path) => Self::open(path, path, auth.to_owned()),
+                None => match auth.split_once(',') {
+                    Some((r, w)) => Self::open(
+                        &format!("/dev/fd/{}", r),
+                        &format!("/dev/fd/{}", w),
+                        auth.to_owned(),
+                    ),
+                    None => Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid --jobserver-auth string '{}'", auth),
+                    )),
+                },
+            })
+        }
+
+        /// opens the two ends of a jobserver, the read end without blocking
+        /// reopening the descriptors of a pipe gives this make its own view of
+        /// them, so other makes aren't affected by it not blocking
+        fn open(read: &str, write: &str, auth: String) -> io::Result<Self> {
+            let read = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(read)?;
+            let write = OpenOptions::new().write(true).open(write)?;
+            Ok(Self {
+                read,
+                write,
+                auth,
+                fifo: None,
+                _pipe: None,
+            })
+        }
+
+        /// the value of --jobserver-auth which sub-makes are given
+        pub(crate) fn auth(&self) -> &str {
+            &self.auth
+        }
+
+        /// takes a token if one is free, returning None otherwise
+        pub(crate) fn try_acquire(&mut self) -> Option<u8> {
+            let mut token = [0];
+            match self.read.read(&mut token) {
+                Ok(1) => Some(token[0]),
+                _ => None,
+            }
+        }
+
+        /// returns a token once the job it was taken for has finished
+        pub(crate) fn release(&mut self, token: u8) {
+            // a make whose tokens can't be returned has nothing better to do
+            let _ = self.write.write_all(&[token]);
+        }
+    }
+
+    impl Drop for Jobserver {
+        fn drop(&mut self) {
+            if let Some(path) = &self.fifo {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod fallback {
+    use std::io::{self, ErrorKind};
+
+    use super::JobserverStyle;
+
+    /// Without unix pipes there is no jobserver, so none can be created or
+    /// joined and make runs one job at a time
+    pub(crate) enum Jobserver {}
+
+    impl Jobserver {
+        pub(crate) fn create(_: usize, _: JobserverStyle) -> io::Result<Self> {
+            Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "the jobserver needs unix pipes",
+            ))
+        }
+
+        pub(crate) fn join(_: &str) -> Option<io::Result<Self>> {
+            None
+        }
+
+        pub(crate) fn auth(&self) -> &str {
+            match *self {}
+        }
+
+        pub(crate) fn try_acquire(&mut self) -> Option<u8> {
+            match *self {}
+        }
+
+        pub(crate) fn release(&mut self, _: u8) {
+            match *self {}
+        }
+    }
+}
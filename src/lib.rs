@@ -1,3 +1,11 @@
 // Expose makefile and loader modules
+mod build;
+mod database;
+mod expand;
+mod functions;
+pub mod jobserver;
 pub mod loader;
 pub mod makefile;
+pub mod output;
+pub mod registry;
+mod variables;
@@ -2,66 +2,179 @@ use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
-use std::process::Command;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+use std::sync::Arc;
 
-use crate::makefile::{FinalRule, MakeFile};
+use crate::expand::{
+    error, expand, get_var_trimmed, located, read_reference, substitute_var, Bindings, Location,
+    Scope, EOF_MESSAGE,
+};
+use crate::functions::wildcard;
+use crate::makefile::{search_dirs, FinalRule, MakeFile, MissingInclude};
+use crate::registry::{FunctionRegistry, MakeFunction};
+use crate::variables::{Flavor, Origin, Variable};
 
-// define a consistent message to produce on EOF
-const EOF_MESSAGE: &str = "Unexpected EOF";
+// directories searched for included makefiles after any given with -I
+const DEFAULT_INCLUDE_DIRS: &[&str] = &["/usr/local/include", "/usr/gnu/include", "/usr/include"];
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-enum Var {
-    Complex,
-    Simple,
-}
+// the optional GNU make features which are supported, listed in .FEATURES
+const FEATURES: &[&str] = &["shell-export", "jobserver", "jobserver-fifo", "output-sync"];
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 enum State {
-    Left(String), // Processing
-    // NOTE: the Var component of RightVariable is never used, is it needed?
-    RightVariable(String, Var, String), // Variable name, Is a complex variable, Processing
-    RightRule(Vec<String>, String),     // Target names, Processing
+    Left(String),                                           // Processing
+    RightVariable(String, Flavor, String),                  // Variable name, Flavor, Processing
+    RightRule(Vec<String>, String),                         // Target names, Processing
     Recipes(Vec<String>, Vec<String>, Vec<String>, String), // Targets, Prereqs, Current list, Processing
 }
 
+/// An iterator over the characters of a makefile which keeps track of
+/// the line that the last character came from
+struct Source<'a> {
+    it: Peekable<Chars<'a>>,
+    line: usize,
+    last: Option<char>,
+    fixed: bool, // Whether every line is reported as the first, as for evaluated text
+}
+
+impl<'a> Source<'a> {
+    fn new(content: &'a str, line: usize, fixed: bool) -> Self {
+        Self {
+            it: content.chars().peekable(),
+            line,
+            last: None,
+            fixed,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.it.peek()
+    }
+}
+
+impl Iterator for Source<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if self.last == Some('\n') && !self.fixed {
+            self.line += 1;
+        }
+        self.last = self.it.next();
+        self.last
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 struct Rule {
     targets: Vec<String>,
     prereqs: Vec<String>,
     recipes: Vec<String>,
+    lines: Vec<usize>,          // The line each recipe was read from
+    location: Option<Location>, // Where the recipes start
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct MakeFileLoader {
-    var_map: HashMap<String, String>,
+    var_map: HashMap<String, Variable>,
     rule_list: Vec<Rule>,
     include_list: Vec<String>,
+    missing_includes: Vec<MissingInclude>,
+    bindings: Bindings,
+    filename: String, // The makefile currently being read, for reporting errors
+    line: usize,
+    export_all: bool, // Whether variables are exported without being named
+    vpaths: Vec<(String, Vec<String>)>, // Patterns and the directories to search for them
+    include_dirs: Vec<String>, // Directories given with -I
+    functions: FunctionRegistry,
 }
 
 impl Default for MakeFileLoader {
     fn default() -> Self {
-        let mut var_map: HashMap<String, String> = HashMap::new();
+        let mut var_map: HashMap<String, Variable> = HashMap::new();
+
+        // import the environment, apart from SHELL which is never inherited
+        for (name, value) in env::vars_os() {
+            if let (Ok(name), Ok(value)) = (name.into_string(), value.into_string()) {
+                if name != "SHELL" {
+                    let mut var = Variable::new(value, Flavor::Recursive, Origin::Environment);
+                    var.set_exported(true);
+                    var_map.insert(name, var);
+                }
+            }
+        }
+
+        // commands are run by the shell, which is configured with these
+        var_map.insert(
+            String::from("SHELL"),
+            Variable::simple("/bin/sh", Origin::Default),
+        );
+        var_map.insert(
+            String::from(".SHELLFLAGS"),
+            Variable::simple("-c", Origin::Default),
+        );
+
+        var_map
+            .entry(String::from("CC"))
+            .or_insert_with(|| Variable::simple("cc", Origin::Default));
+        var_map
+            .entry(String::from("CXX"))
+            .or_insert_with(|| Variable::simple("c++", Origin::Default));
 
         // construct the value for the MAKE variable
         var_map.insert(
             String::from("MAKE"),
-            env::current_exe()
-                .ok()
-                .map(PathBuf::into_os_string)
-                .map(|oss| oss.into_string().ok())
-                .flatten()
-                .unwrap_or_else(|| String::from("make")),
+            Variable::simple(
+                env::current_exe()
+                    .ok()
+                    .map(PathBuf::into_os_string)
+                    .and_then(|oss| oss.into_string().ok())
+                    .unwrap_or_else(|| String::from("make")),
+                Origin::Default,
+            ),
         );
 
-        var_map.insert(String::from("CC"), String::from("cc"));
-        var_map.insert(String::from("CXX"), String::from("c++"));
+        // special variables describing make itself
+        var_map.insert(
+            String::from("CURDIR"),
+            Variable::simple(
+                env::current_dir()
+                    .ok()
+                    .map(PathBuf::into_os_string)
+                    .and_then(|oss| oss.into_string().ok())
+                    .unwrap_or_default(),
+                Origin::File,
+            ),
+        );
+        var_map.insert(
+            String::from("MAKE_VERSION"),
+            Variable::simple(env!("CARGO_PKG_VERSION"), Origin::Default),
+        );
+        var_map.insert(
+            String::from(".FEATURES"),
+            Variable::simple(FEATURES.join(" "), Origin::Default),
+        );
+        var_map.insert(
+            String::from("MAKEFILE_LIST"),
+            Variable::simple("", Origin::File),
+        );
 
-        Self {
+        let mut loader = Self {
             var_map,
             rule_list: Vec::new(),
             include_list: Vec::new(),
-        }
+            missing_includes: Vec::new(),
+            bindings: Bindings::default(),
+            filename: String::new(),
+            line: 0,
+            export_all: false,
+            vpaths: Vec::new(),
+            include_dirs: Vec::new(),
+            functions: FunctionRegistry::new(),
+        };
+        loader.update_include_dirs();
+        loader
     }
 }
 
@@ -77,31 +190,177 @@ impl MakeFileLoader {
         let mut content = String::new();
         file.read_to_string(&mut content)?;
 
+        self.parse(&content, 1, false)
+    }
+
+    /// loads in all the variables and targets from the Makefile at a path
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let filename = path.as_ref().display().to_string();
+        let mut file = File::open(path.as_ref())
+            .map_err(|err| located(error(format!("{}: {}", filename, err)), None))?;
+        self.load_from(&mut file, &filename)
+    }
+
+    /// records how many times make has restarted after remaking makefiles
+    pub fn set_restarts(&mut self, restarts: usize) {
+        self.var_map.insert(
+            String::from("MAKE_RESTARTS"),
+            Variable::simple(restarts.to_string(), Origin::Override),
+        );
+    }
+
+    /// sets the goals which were given on the command line
+    pub fn set_goals(&mut self, goals: &[String]) {
+        self.var_map.insert(
+            String::from("MAKECMDGOALS"),
+            Variable::simple(goals.join(" "), Origin::Default),
+        );
+    }
+
+    /// adds a directory to search for included makefiles, as is done by -I
+    pub fn add_include_dir(&mut self, dir: impl AsRef<Path>) {
+        let dir = dir.as_ref().display().to_string();
+        if !self.include_dirs.contains(&dir) {
+            self.include_dirs.push(dir);
+        }
+        self.update_include_dirs();
+    }
+
+    /// loads the makefiles listed in the MAKEFILES variable, ignoring any
+    /// which don't exist
+    pub fn load_env_makefiles(&mut self) -> std::io::Result<()> {
+        // these never provide the default goal
+        let default_goal = self.var_map.get(".DEFAULT_GOAL").cloned();
+        let names = get_var_trimmed(self, "MAKEFILES")?;
+        for name in names.split_whitespace() {
+            self.include(name, true)?;
+        }
+        match default_goal {
+            Some(goal) => self.var_map.insert(String::from(".DEFAULT_GOAL"), goal),
+            None => self.var_map.remove(".DEFAULT_GOAL"),
+        };
+        Ok(())
+    }
+
+    /// registers a function implemented in Rust, which makefiles can then call
+    /// as `$(name args)` like any built-in function
+    pub fn register_function(
+        &mut self,
+        name: impl Into<String>,
+        function: impl MakeFunction + 'static,
+    ) -> std::io::Result<()> {
+        self.functions.register(name, function)
+    }
+
+    /// the directories searched for included makefiles which exist
+    fn search_include_dirs(&self) -> Vec<String> {
+        self.include_dirs
+            .iter()
+            .map(String::as_str)
+            .chain(DEFAULT_INCLUDE_DIRS.iter().copied())
+            .filter(|dir| Path::new(dir).is_dir())
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// keeps .INCLUDE_DIRS up to date with the include search path
+    fn update_include_dirs(&mut self) {
+        let dirs = self.search_include_dirs().join(" ");
+        self.var_map.insert(
+            String::from(".INCLUDE_DIRS"),
+            Variable::simple(dirs, Origin::Default),
+        );
+    }
+
+    /// reads an included makefile, which is looked for in the current
+    /// directory and then along the include search path
+    fn include(&mut self, name: &str, optional: bool) -> std::io::Result<()> {
+        let path = if Path::new(name).exists() || Path::new(name).is_absolute() {
+            Some(name.to_owned())
+        } else {
+            self.search_include_dirs()
+                .iter()
+                .map(|dir| format!("{}/{}", dir.trim_end_matches('/'), name))
+                .find(|path| Path::new(path).exists())
+        };
+
+        match path.map(|path| (File::open(&path), path)) {
+            Some((Ok(mut file), path)) => self.load_from(&mut file, &path),
+            Some((Err(err), _)) if !optional || err.kind() != std::io::ErrorKind::NotFound => {
+                Err(error(format!("{}: {}", name, err)))
+            }
+            // missing makefiles may yet be made by a rule, see MakeFile::remake_makefiles
+            _ => {
+                self.missing_includes.push(MissingInclude {
+                    name: name.to_owned(),
+                    optional,
+                    location: self.location(),
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// loads a Makefile, reporting errors against the given filename
+    fn load_from(&mut self, file: &mut File, filename: &str) -> std::io::Result<()> {
+        // keep MAKEFILE_LIST up to date with every makefile read
+        self.include_list.push(filename.to_owned());
+        self.var_map.insert(
+            String::from("MAKEFILE_LIST"),
+            Variable::simple(self.include_list.join(" "), Origin::File),
+        );
+
+        let filename = std::mem::replace(&mut self.filename, filename.to_owned());
+        let line = self.line;
+        let result = self.load(file);
+        self.filename = filename;
+        self.line = line;
+        result
+    }
+
+    /// parses the text of a Makefile, the first line having the given number,
+    /// which every line has if it's fixed
+    fn parse(&mut self, content: &str, line: usize, fixed: bool) -> std::io::Result<()> {
+        // make sure the last line is terminated
+        let mut content = content.to_owned();
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+
         // get an iterator over its contents
-        let mut it = content.chars().peekable();
+        let mut it = Source::new(&content, line, fixed);
         let mut state = State::Left(String::new());
 
         // when the top is true we don't skip else we skip
         let mut skip_stack = vec![true];
         let mut skip_buf = String::new();
+        // conditionals opened while skipping, whose else and endif are skipped too
+        let mut skip_depth = 0;
+
+        // the lines which the recipes of the current rule were read from
+        let mut recipe_lines = Vec::new();
 
         while let Some(c) = it.next() {
-            let skip = !skip_stack.last().expect("missmatched conditonals");
+            self.line = it.line;
+            let skip = skip_stack.last() == Some(&false);
             if skip {
                 match c {
                     '\n' => {
+                        let directive = skip_buf.split_whitespace().next().unwrap_or_default();
                         match skip_buf.trim() {
-                            "else" => {
+                            _ if ["ifdef", "ifndef", "ifeq", "ifneq"].contains(&directive) => {
+                                skip_depth += 1
+                            }
+                            "else" if skip_depth == 0 => {
                                 if let Some(last) = skip_stack.last_mut() {
                                     // we know we need to stop skipping
                                     // in the else branch if we're here
                                     *last = true
-                                } else {
-                                    panic!("missmatched conditionals")
                                 }
                             }
+                            "endif" if skip_depth > 0 => skip_depth -= 1,
                             "endif" => {
-                                skip_stack.pop().expect("missmatched conditionals");
+                                skip_stack.pop();
                             }
                             _ => {}
                         }
@@ -113,34 +372,49 @@ impl MakeFileLoader {
                 }
             } else {
                 match c {
-                    '$' => {
-                        let work = match state {
-                            State::Left(ref mut work) => work,
-                            State::RightRule(_, ref mut work) => work,
-                            State::RightVariable(_, _, ref mut work) => work,
-                            State::Recipes(_, _, _, ref mut work) => work,
-                        };
-                        work.push_str(self.substitute_var(&mut it).as_str());
-                    }
+                    '$' => match state {
+                        // recipes are expanded when they are run, and
+                        // recursive variables when they are referenced
+                        State::Recipes(_, _, _, ref mut work)
+                        | State::RightVariable(_, Flavor::Recursive, ref mut work) => {
+                            work.push('$');
+                            work.push_str(
+                                &read_reference(&mut it).map_err(|err| self.locate(err))?,
+                            );
+                        }
+                        State::Left(ref mut work)
+                        | State::RightRule(_, ref mut work)
+                        | State::RightVariable(_, Flavor::Simple, ref mut work) => {
+                            let value = substitute_var(self, &mut it);
+                            work.push_str(&value.map_err(|err| self.locate(err))?);
+                        }
+                    },
                     '#' => {
                         while *(it.peek().expect(EOF_MESSAGE)) != '\n' {
                             it.next();
                         }
                     }
                     ':' => match state {
+                        State::Left(ref mut work)
+                            if is_define(work)
+                                || (is_vpath(work) && work.split_whitespace().count() > 1) =>
+                        {
+                            work.push(':');
+                        }
                         State::Left(prev) => {
                             let next = it.peek();
                             state = match next {
                                 Some(':') => {
                                     it.next();
                                     if it.next() != Some('=') {
-                                        panic!("Syntax error");
+                                        let err = error("double-colon rules are not supported");
+                                        return Err(self.locate(err));
                                     }
-                                    State::RightVariable(prev, Var::Complex, String::new())
+                                    State::RightVariable(prev, Flavor::Simple, String::new())
                                 }
                                 Some('=') => {
                                     it.next();
-                                    State::RightVariable(prev, Var::Complex, String::new())
+                                    State::RightVariable(prev, Flavor::Simple, String::new())
                                 }
                                 _ => State::RightRule(
                                     prev.split_whitespace().map(str::to_string).collect(),
@@ -154,12 +428,18 @@ impl MakeFileLoader {
                         State::Recipes(_, _, _, ref mut work) => {
                             work.push(':');
                         }
-                        _ => panic!("Syntax error"),
+                        State::RightRule(_, _) => {
+                            return Err(self.locate(error("multiple target patterns")));
+                        }
                     },
                     '=' => {
                         match state {
+                            State::Left(ref mut work) if is_define(work) => {
+                                work.push('=');
+                            }
                             State::Left(prev) => {
-                                state = State::RightVariable(prev, Var::Simple, String::new());
+                                state =
+                                    State::RightVariable(prev, Flavor::Recursive, String::new());
                             }
                             State::RightVariable(_, _, ref mut work) => {
                                 work.push('=');
@@ -167,67 +447,140 @@ impl MakeFileLoader {
                             State::Recipes(_, _, _, ref mut work) => {
                                 work.push('=');
                             }
-                            _ => panic!("Syntax error"),
+                            State::RightRule(_, _) => {
+                                let err = error("target-specific variables are not supported");
+                                return Err(self.locate(err));
+                            }
                         };
                     }
                     '\n' => {
                         state = match state {
                             State::Left(x) if x.trim().is_empty() => State::Left(String::new()),
-                            State::Left(x) if x.trim().starts_with("include ") => {
-                                let filename = x.trim()[8..].trim();
-                                let file = File::open(filename);
-                                if let Ok(mut file) = file {
-                                    self.load(&mut file)?;
+                            State::Left(x) if is_include(&x) => {
+                                let mut words = x.split_whitespace();
+                                let optional = words.next() != Some("include");
+                                // included names may be globs, which are kept
+                                // as they are if nothing matches
+                                let names: Vec<String> = words
+                                    .flat_map(|word| match wildcard(word) {
+                                        paths if paths.is_empty() => vec![word.to_owned()],
+                                        paths => paths,
+                                    })
+                                    .collect();
+                                for name in names {
+                                    self.include(&name, optional)
+                                        .map_err(|err| self.locate(err))?;
+                                }
+                                State::Left(String::new())
+                            }
+                            State::Left(x) if is_define(&x) => {
+                                let rest = x.trim()[6..].trim();
+                                let (name, flavor) = if let Some(name) =
+                                    rest.strip_suffix("::=").or_else(|| rest.strip_suffix(":="))
+                                {
+                                    (name, Flavor::Simple)
                                 } else {
-                                    panic!("Couldn't open {}", filename);
+                                    (rest.strip_suffix('=').unwrap_or(rest), Flavor::Recursive)
+                                };
+
+                                let mut value = read_define(&mut it).map_err(|e| self.locate(e))?;
+                                if flavor == Flavor::Simple {
+                                    value = expand(self, &value).map_err(|e| self.locate(e))?;
                                 }
+                                self.define_variable(name, value, flavor);
                                 State::Left(String::new())
                             }
-                            State::Left(x) if x.trim().starts_with("-include ") => {
-                                let filename = x.trim()[9..].trim();
-                                let file = File::open(filename);
-                                if let Ok(mut file) = file {
-                                    self.load(&mut file)?;
+                            State::Left(x)
+                                if matches!(
+                                    x.split_whitespace().next(),
+                                    Some("export") | Some("unexport")
+                                ) =>
+                            {
+                                let mut names = x.split_whitespace();
+                                let export = names.next() == Some("export");
+                                let names: Vec<&str> = names.collect();
+                                if names.is_empty() {
+                                    // on its own export applies to every variable
+                                    self.export_all = export;
+                                    if export {
+                                        for var in self.var_map.values_mut() {
+                                            var.set_exported(true);
+                                        }
+                                    }
+                                }
+                                for name in names {
+                                    if let Some(var) = self.var_map.get_mut(name) {
+                                        var.set_exported(export);
+                                    } else if export {
+                                        self.define_variable(
+                                            name,
+                                            String::new(),
+                                            Flavor::Recursive,
+                                        )
+                                        .set_exported(true);
+                                    }
+                                }
+                                State::Left(String::new())
+                            }
+                            State::Left(x) if is_vpath(&x) => {
+                                let mut words = x.split_whitespace().skip(1);
+                                match words.next() {
+                                    // without directories, the search path is cleared
+                                    None => self.vpaths.clear(),
+                                    Some(pattern) => {
+                                        let dirs =
+                                            search_dirs(&words.collect::<Vec<_>>().join(" "));
+                                        if dirs.is_empty() {
+                                            self.vpaths.retain(|(p, _)| p != pattern);
+                                        } else {
+                                            self.vpaths.push((pattern.to_owned(), dirs));
+                                        }
+                                    }
                                 }
                                 State::Left(String::new())
                             }
                             State::Left(x) if x.trim().starts_with("ifdef ") => {
                                 let rhs = x.trim()[5..].trim();
-                                let rhs = if rhs.starts_with("$") {
-                                    self.substitute_var(&mut x[1..].chars())
-                                } else {
-                                    rhs.to_string()
-                                };
-                                skip_stack.push(self.var_map.contains_key(&rhs));
+                                skip_stack.push(self.var_map.contains_key(rhs));
                                 State::Left(String::new())
                             }
                             State::Left(x) if x.trim().starts_with("ifndef ") => {
                                 let rhs = x.trim()[6..].trim();
-                                let rhs = if rhs.starts_with("$") {
-                                    self.substitute_var(&mut x[1..].chars())
-                                } else {
-                                    rhs.to_string()
-                                };
-                                skip_stack.push(!self.var_map.contains_key(&rhs));
+                                skip_stack.push(!self.var_map.contains_key(rhs));
                                 State::Left(String::new())
                             }
                             State::Left(x) if x.trim() == "else" => {
+                                // the outermost entry isn't a conditional
+                                if skip_stack.len() == 1 {
+                                    return Err(self.locate(error("extraneous 'else'")));
+                                }
                                 if let Some(last) = skip_stack.last_mut() {
                                     // we know we need to start skipping
                                     // in the else branch if we're here
                                     *last = false
-                                } else {
-                                    panic!("missmatched conditionals")
                                 }
                                 State::Left(String::new())
                             }
                             State::Left(x) if x.trim() == "endif" => {
-                                skip_stack.pop().expect("missmatched conditionals");
+                                if skip_stack.len() == 1 {
+                                    return Err(self.locate(error("extraneous 'endif'")));
+                                }
+                                skip_stack.pop();
                                 State::Left(String::new())
                             }
-                            State::Left(_) => panic!("Syntax error"),
-                            State::RightVariable(name, _, value) => {
-                                self.var_map.insert(name.trim().to_owned(), value);
+                            State::Left(_) => return Err(self.locate(error("missing separator"))),
+                            State::RightVariable(name, flavor, value) => {
+                                // leading whitespace is never part of the value
+                                let value = value.trim_start().to_owned();
+                                match name.trim().strip_prefix("export") {
+                                    Some(name) if name.starts_with(char::is_whitespace) => {
+                                        self.define_variable(name, value, flavor)
+                                            .set_exported(true);
+                                    }
+                                    _ => {
+                                        self.define_variable(&name, value, flavor);
+                                    }
+                                }
                                 State::Left(String::new())
                             }
                             State::RightRule(targets, prereqs) => {
@@ -241,6 +594,7 @@ impl MakeFileLoader {
                                 match it.peek() {
                                     Some('\t') => {
                                         it.next(); // Skip \t
+                                        recipe_lines = vec![it.line];
                                         State::Recipes(
                                             targets,
                                             prereqs
@@ -257,10 +611,13 @@ impl MakeFileLoader {
                                             .map(str::to_string)
                                             .collect();
                                         let recipes = Vec::new();
-                                        self.rule_list.push(Rule {
+                                        let location = self.location();
+                                        self.add_rule(Rule {
                                             targets,
                                             prereqs,
                                             recipes,
+                                            lines: Vec::new(),
+                                            location,
                                         });
                                         State::Left(String::new())
                                     }
@@ -278,13 +635,20 @@ impl MakeFileLoader {
                                 match it.peek() {
                                     Some('\t') => {
                                         it.next(); // Skip \t
+                                        recipe_lines.push(it.line);
                                         State::Recipes(targets, prereqs, recipes, String::new())
                                     }
                                     _ => {
-                                        self.rule_list.push(Rule {
+                                        let lines = std::mem::take(&mut recipe_lines);
+                                        let location = self
+                                            .location()
+                                            .map(|(filename, _)| (filename, lines[0]));
+                                        self.add_rule(Rule {
                                             targets,
                                             prereqs,
                                             recipes,
+                                            lines,
+                                            location,
                                         });
                                         State::Left(String::new())
                                     }
@@ -325,36 +689,53 @@ impl MakeFileLoader {
                 }
             }
         }
+
+        if skip_stack.len() > 1 {
+            return Err(self.locate(error("missing 'endif'")));
+        }
         Ok(())
     }
 
-    fn substitute_var(&self, it: &mut dyn Iterator<Item = char>) -> String {
-        match it.next().expect(EOF_MESSAGE) {
-            // Delay processing until target processing
-            '@' => String::from("$@"),
-            '?' => String::from("$?"),
-            '<' => String::from("$<"),
-
-            '$' => String::from("$"),
+    /// adds a rule, whose first ordinary target becomes the default goal
+    /// unless one has already been chosen
+    fn add_rule(&mut self, rule: Rule) {
+        let has_goal = self
+            .var_map
+            .get(".DEFAULT_GOAL")
+            .is_some_and(|goal| !goal.value().trim().is_empty());
+        if !has_goal {
+            if let Some(target) = rule.targets.iter().find(|target| is_goal(target)) {
+                self.var_map.insert(
+                    String::from(".DEFAULT_GOAL"),
+                    Variable::simple(target.clone(), Origin::Default),
+                );
+            }
+        }
+        self.rule_list.push(rule);
+    }
 
-            // handle bracketed variables
-            '(' => get_var_trimmed(
-                &self.var_map,
-                read_bracketed_var(it, ")", |it| self.substitute_var(it)),
-            ),
-            '{' => get_var_trimmed(
-                &self.var_map,
-                read_bracketed_var(it, "}", |it| self.substitute_var(it)),
-            ),
+    /// defines a variable read from a makefile, keeping it exported if it was
+    fn define_variable(&mut self, name: &str, value: String, flavor: Flavor) -> &mut Variable {
+        let name = name.trim();
+        let exported = self.export_all || self.var_map.get(name).is_some_and(Variable::exported);
+        let mut var = Variable::new(value, flavor, Origin::File);
+        var.set_exported(exported);
+        self.var_map.insert(name.to_owned(), var);
+        self.var_map
+            .get_mut(name)
+            .expect("variable was just defined")
+    }
 
-            x => panic!("${} ???", x),
-        }
+    /// attributes an error to the line currently being read
+    fn locate(&self, err: std::io::Error) -> std::io::Error {
+        located(err, self.location().as_ref())
     }
 
     /// Finalise method consumes the loader object and builds a finalised
     /// version of all the rules, returning the finalised MakeFile object.
     pub fn finalise(self) -> MakeFile {
         let mut final_rule_list: Vec<FinalRule> = Vec::new();
+        let mut final_rule_index: HashMap<String, usize> = HashMap::new();
         let mut append_implicit_rules = true;
         let mut inference_rules_warning = false;
 
@@ -363,6 +744,10 @@ impl MakeFileLoader {
             var_map,
             rule_list,
             include_list,
+            missing_includes,
+            vpaths,
+            functions,
+            ..
         } = self;
 
         for rule in rule_list {
@@ -372,18 +757,14 @@ impl MakeFileLoader {
                     handled = true; // We should be POSIX compliant enough; no special flags needed
                 } else if rule.targets[0] == ".SUFFIXES" {
                     handled = true;
+                    // suffix rules are unimplemented, so any suffixes listed are ignored
                     if rule.prereqs.is_empty() {
                         append_implicit_rules = false;
-                    } else if rule.prereqs.len() != 1
-                        || rule.prereqs[0] != ".hpux_make_needs_suffix_list"
-                    {
-                        // Workaround for CMake's workaround to a problem we don't have
-                        panic!("Unimplemented");
                     }
                 } else {
                     let mut it = rule.targets[0].chars();
-                    if it.next().expect(EOF_MESSAGE) == '.' {
-                        if it.next().expect(EOF_MESSAGE).is_uppercase() {
+                    if it.next() == Some('.') {
+                        if it.next().is_some_and(char::is_uppercase) {
                             /*
                             println!(
                                 "Warning: {} is unimplemented; treating as a normal rule for now",
@@ -400,18 +781,25 @@ impl MakeFileLoader {
             }
             if !handled {
                 for target in rule.targets {
-                    if let Some(existing_rule) =
-                        final_rule_list.iter_mut().find(|r| r.target() == target)
-                    {
+                    if let Some(&index) = final_rule_index.get(&target) {
+                        let existing_rule = &mut final_rule_list[index];
                         existing_rule
                             .prereqs_mut()
                             .append(&mut rule.prereqs.clone());
-                        *existing_rule.recipes_mut() = rule.recipes.clone();
+                        // rules without recipes only add prerequisites
+                        if !rule.recipes.is_empty() {
+                            *existing_rule.recipes_mut() = rule.recipes.clone();
+                            *existing_rule.lines_mut() = rule.lines.clone();
+                            *existing_rule.location_mut() = rule.location.clone();
+                        }
                     } else {
+                        final_rule_index.insert(target.clone(), final_rule_list.len());
                         final_rule_list.push(FinalRule::new(
                             target,
                             rule.prereqs.clone(),
                             rule.recipes.clone(),
+                            rule.lines.clone(),
+                            rule.location.clone(),
                         ));
                     }
                 }
@@ -422,58 +810,301 @@ impl MakeFileLoader {
             // println!("Warning: POSIX-style inference rules are unimplemented");
         }
 
-        MakeFile::new(var_map, final_rule_list, include_list)
+        MakeFile::new(
+            var_map,
+            final_rule_list,
+            include_list,
+            missing_includes,
+            vpaths,
+            functions,
+        )
+    }
+}
+
+/// lists the names of every defined variable, as is done by .VARIABLES
+pub(crate) fn variable_list(var_map: &HashMap<String, Variable>) -> Variable {
+    let mut names: Vec<&str> = var_map.keys().map(String::as_str).collect();
+    names.push(".VARIABLES");
+    names.sort_unstable();
+    Variable::simple(names.join(" "), Origin::Default)
+}
+
+/// checks whether a line starts a multi-line variable definition
+fn is_define(line: &str) -> bool {
+    line.split_whitespace().next() == Some("define")
+}
+
+/// checks whether a target can be the default goal, which special targets
+/// and patterns can't
+fn is_goal(target: &str) -> bool {
+    (!target.starts_with('.') || target.contains('/')) && !target.contains('%')
+}
+
+/// checks whether a line is an include directive
+fn is_include(line: &str) -> bool {
+    matches!(
+        line.split_whitespace().next(),
+        Some("include") | Some("-include") | Some("sinclude")
+    )
+}
+
+/// checks whether a line is a vpath directive
+fn is_vpath(line: &str) -> bool {
+    line.split_whitespace().next() == Some("vpath")
+}
+
+/// reads the body of a multi-line variable definition up to its endef
+fn read_define(it: &mut Source) -> std::io::Result<String> {
+    let mut lines = Vec::new();
+    let mut depth = 0;
+    while it.peek().is_some() {
+        let line: String = it.by_ref().take_while(|c| *c != '\n').collect();
+        match line.split_whitespace().next() {
+            Some("endef") if depth == 0 => return Ok(lines.join("\n")),
+            Some("endef") => depth -= 1,
+            Some("define") => depth += 1,
+            _ => {}
+        }
+        lines.push(line);
     }
+    Err(error("missing 'endef', unterminated 'define'"))
 }
 
-/// gets a variable from a variable map and trims it
-pub(crate) fn get_var_trimmed(
-    var_map: &HashMap<String, String>,
-    variable: impl AsRef<str>,
-) -> String {
-    let variable = variable.as_ref();
-    if variable.starts_with("shell ") {
-        let output = Command::new("sh").arg("-c").arg(&variable[6..]).output();
-        if let Ok(output) = output {
-            String::from_utf8(output.stdout)
-                .expect("Command didn't output valid UTF-8")
-                .replace("\n", "")
+impl Scope for MakeFileLoader {
+    fn lookup(&self, name: &str) -> Option<Variable> {
+        match name {
+            ".VARIABLES" => Some(variable_list(&self.var_map)),
+            name => self.var_map.get(name).cloned(),
+        }
+    }
+
+    fn variable_names(&self) -> Vec<String> {
+        self.var_map.keys().cloned().collect()
+    }
+
+    fn define(&mut self, name: &str, var: Variable) {
+        self.var_map.insert(name.to_owned(), var);
+    }
+
+    fn bindings(&mut self) -> &mut Bindings {
+        &mut self.bindings
+    }
+
+    fn function(&self, name: &str) -> Option<Arc<dyn MakeFunction>> {
+        self.functions.get(name)
+    }
+
+    fn location(&self) -> Option<Location> {
+        if self.filename.is_empty() {
+            None
         } else {
-            String::new()
+            Some((self.filename.clone(), self.line))
         }
-    } else {
-        var_map
-            .get(variable)
-            .map(|s| s.as_str().trim().to_owned())
-            .unwrap_or_else(String::new)
+    }
+
+    fn eval(&mut self, text: &str) -> std::io::Result<()> {
+        // evaluated text is reported as coming from the line which evaluated it
+        let line = self.line;
+        let result = self.parse(text, line, true);
+        self.line = line;
+        result
     }
 }
 
-/// function for reading a bracketed variable
-/// it is parameterised by a function to substitute
-pub(crate) fn read_bracketed_var<S>(
-    it: &mut dyn Iterator<Item = char>,
-    bracket: impl AsRef<str>,
-    sub_var: S,
-) -> String
-where
-    S: Fn(&mut dyn Iterator<Item = char>) -> String,
-{
-    // abstract reading a variable out to a method
-    let mut variable: String = String::new();
-    let mut c: String;
-
-    while {
-        c = match it.next().expect(EOF_MESSAGE) {
-            '#' => panic!("Syntax error"),
-            '$' => sub_var(it),
-            x => x.to_string(),
-        };
-        c.as_str()
-    } != bracket.as_ref()
-    {
-        variable.push_str(c.as_str());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// reads a makefile from text, as if from a file named Makefile
+    fn load(text: &str) -> std::io::Result<MakeFileLoader> {
+        let mut loader = MakeFileLoader::new();
+        loader.filename = String::from("Makefile");
+        loader.parse(text, 1, false)?;
+        Ok(loader)
+    }
+
+    /// the value of a variable, trimmed as it is when referenced
+    fn value(loader: &MakeFileLoader, name: &str) -> String {
+        loader.var_map[name].value().trim().to_owned()
+    }
+
+    #[test]
+    fn define_keeps_every_line() {
+        let loader = load(
+            "define two-lines\n\
+             first $(x)\n\
+             \tsecond\n\
+             endef\n\
+             define outer\n\
+             define inner\n\
+             endef\n\
+             endef\n",
+        )
+        .unwrap();
+        assert_eq!(value(&loader, "two-lines"), "first $(x)\n\tsecond");
+        assert_eq!(value(&loader, "outer"), "define inner\nendef");
+    }
+
+    #[test]
+    fn define_needs_endef() {
+        let err = load("define x\nvalue\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Makefile:1: *** missing 'endef', unterminated 'define'.  Stop."
+        );
+    }
+
+    #[test]
+    fn eval_reads_text_as_makefile() {
+        let loader = load(
+            "define program\n\
+             $(1): $(1).o\n\
+             \tcc -o $$@ $$^\n\
+             $(1)_name := $(1)\n\
+             endef\n\
+             $(eval $(call program,hello))\n\
+             $(eval x = 1)\n",
+        )
+        .unwrap();
+        assert_eq!(value(&loader, "hello_name"), "hello");
+        assert_eq!(value(&loader, "x"), "1");
+        let rule = &loader.rule_list[0];
+        assert_eq!(rule.targets, ["hello"]);
+        assert_eq!(rule.prereqs, ["hello.o"]);
+        assert_eq!(rule.recipes, ["cc -o $@ $^"]);
     }
 
-    variable
+    #[test]
+    fn eval_errors_are_reported_at_the_eval() {
+        let err = load(
+            "define body\n\
+             a = 1\n\
+             b := $$(word 0,a)\n\
+             endef\n\
+             \n\
+             $(eval $(body))\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Makefile:6: *** first argument to 'word' function must be greater than 0.  Stop."
+        );
+    }
+
+    #[test]
+    fn error_stops_at_its_line() {
+        let err = load("x = boom\n\n$(error $(x) here)\n").unwrap_err();
+        assert_eq!(err.to_string(), "Makefile:3: *** boom here.  Stop.");
+    }
+
+    #[test]
+    fn warning_and_info_expand_to_nothing() {
+        let loader = load("x := <$(warning careful)$(info hello)>\n").unwrap();
+        assert_eq!(value(&loader, "x"), "<>");
+    }
+
+    #[test]
+    fn shell_output_and_status() {
+        let loader = load(
+            "lines := $(shell printf 'a\\nb\\n\\n')\n\
+             failed := $(shell exit 3)$(.SHELLSTATUS)\n\
+             passed := $(shell true)$(.SHELLSTATUS)\n\
+             export greeting = hello $(lines)\n\
+             hidden = secret\n\
+             env := $(shell echo \"$$greeting/$$hidden\")\n",
+        )
+        .unwrap();
+        assert_eq!(value(&loader, "lines"), "a b");
+        assert_eq!(value(&loader, "failed"), "3");
+        assert_eq!(value(&loader, "passed"), "0");
+        assert_eq!(value(&loader, "env"), "hello a b/");
+    }
+
+    #[test]
+    fn origin_flavor_and_value() {
+        let mut loader = load(
+            "simple := $(CC)\n\
+             recursive = $(simple) $$x\n",
+        )
+        .unwrap();
+        let cases = [
+            ("$(origin simple) $(flavor simple)", "file simple"),
+            ("$(origin recursive) $(flavor recursive)", "file recursive"),
+            ("$(origin CC) $(origin PATH)", "default environment"),
+            ("$(origin nothing) $(flavor nothing)", "undefined undefined"),
+            ("$(foreach v,x,$(origin v))", "automatic"),
+            ("$(value recursive)", "$(simple) $$x"),
+            ("$(value simple)", "cc"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(expand(&mut loader, text).unwrap(), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn special_variables() {
+        let mut loader = load("x = 1\n").unwrap();
+        let curdir = env::current_dir().unwrap().display().to_string();
+        assert_eq!(expand(&mut loader, "$(CURDIR)").unwrap(), curdir);
+        let version = expand(&mut loader, "$(MAKE_VERSION)").unwrap();
+        assert_eq!(version, env!("CARGO_PKG_VERSION"));
+        let variables = expand(&mut loader, "$(.VARIABLES)").unwrap();
+        assert!(variables.split(' ').any(|name| name == "x"));
+        assert!(variables.split(' ').any(|name| name == ".VARIABLES"));
+        // SHELL is never taken from the environment
+        assert!(loader
+            .var_map
+            .get("SHELL")
+            .is_none_or(|var| var.origin() != Origin::Environment));
+        loader.set_goals(&[String::from("a"), String::from("b")]);
+        assert_eq!(expand(&mut loader, "$(MAKECMDGOALS)").unwrap(), "a b");
+    }
+
+    #[test]
+    fn conditionals_nest_inside_skipped_branches() {
+        let loader = load(
+            "ifdef undefined\n\
+             ifndef undefined\n\
+             x = 1\n\
+             else\n\
+             x = 2\n\
+             endif\n\
+             y = skipped\n\
+             else\n\
+             y = kept\n\
+             endif\n",
+        )
+        .unwrap();
+        assert!(!loader.var_map.contains_key("x"));
+        assert_eq!(value(&loader, "y"), "kept");
+    }
+
+    #[test]
+    fn makefile_errors_are_reported() {
+        let cases = [
+            ("else\n", "Makefile:1: *** extraneous 'else'.  Stop."),
+            ("endif\n", "Makefile:1: *** extraneous 'endif'.  Stop."),
+            ("ifdef x\n", "Makefile:1: *** missing 'endif'.  Stop."),
+            (
+                "x = 1\nnonsense\n",
+                "Makefile:2: *** missing separator.  Stop.",
+            ),
+            (
+                "a:: b\n",
+                "Makefile:1: *** double-colon rules are not supported.  Stop.",
+            ),
+            (
+                "a: b: c\n",
+                "Makefile:1: *** multiple target patterns.  Stop.",
+            ),
+            (
+                "a: x = 1\n",
+                "Makefile:1: *** target-specific variables are not supported.  Stop.",
+            ),
+        ];
+        for (text, expected) in cases {
+            let err = load(text).unwrap_err();
+            assert_eq!(err.to_string(), expected, "{}", text);
+        }
+    }
 }
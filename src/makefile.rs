@@ -1,26 +1,47 @@
 use std::collections::HashMap;
 use std::path::Path;
-use std::process::Command;
+use std::sync::Arc;
 use std::time::SystemTime;
 
-// import helper functions from loader module
-use crate::loader::{get_var_trimmed, read_bracketed_var};
+// import helper functions from expand module
+use crate::build::{BuildOptions, Run};
+use crate::database::print_data_base;
+use crate::expand::{error, get_var_trimmed, located, Bindings, Location, Scope};
+use crate::functions::matches;
+use crate::jobserver::JobserverStyle;
+use crate::loader::variable_list;
+use crate::output::OutputSync;
+use crate::registry::{FunctionRegistry, MakeFunction};
+use crate::variables::{Origin, Variable};
+
+// failures to bring targets up to date carry the target and exit status
+pub use crate::build::BuildError;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub(crate) struct FinalRule {
     target: String, // Every rule in the final list only has one target (or target pattern) it provides
     prereqs: Vec<String>,
     recipes: Vec<String>,
+    lines: Vec<usize>,          // The line each recipe was read from
+    location: Option<Location>, // Where the recipes were defined
 }
 
 #[allow(dead_code)]
 impl FinalRule {
     // constructor
-    pub(crate) fn new(target: String, prereqs: Vec<String>, recipes: Vec<String>) -> Self {
+    pub(crate) fn new(
+        target: String,
+        prereqs: Vec<String>,
+        recipes: Vec<String>,
+        lines: Vec<usize>,
+        location: Option<Location>,
+    ) -> Self {
         Self {
             target,
             prereqs,
             recipes,
+            lines,
+            location,
         }
     }
 
@@ -34,6 +55,14 @@ impl FinalRule {
     pub(crate) fn recipes(&self) -> &Vec<String> {
         &self.recipes
     }
+    pub(crate) fn location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+    /// where a line of the recipe was read from
+    pub(crate) fn recipe_location(&self, index: usize) -> Option<Location> {
+        let (file, _) = self.location.as_ref()?;
+        Some((file.clone(), self.lines[index]))
+    }
 
     // mutable member access
     pub(crate) fn target_mut(&mut self) -> &mut str {
@@ -45,154 +74,399 @@ impl FinalRule {
     pub(crate) fn recipes_mut(&mut self) -> &mut Vec<String> {
         &mut self.recipes
     }
+    pub(crate) fn lines_mut(&mut self) -> &mut Vec<usize> {
+        &mut self.lines
+    }
+    pub(crate) fn location_mut(&mut self) -> &mut Option<Location> {
+        &mut self.location
+    }
+}
+
+/// A makefile which was included but couldn't be found
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct MissingInclude {
+    pub(crate) name: String,
+    pub(crate) optional: bool, // Included with -include or sinclude
+    pub(crate) location: Option<Location>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct MakeFile {
-    var_map: HashMap<String, String>,
+    var_map: HashMap<String, Variable>,
     finalised_rules: Vec<FinalRule>,
+    rule_index: HashMap<String, usize>, // The position of each target's rule in finalised_rules
     include_list: Vec<String>,
+    missing_includes: Vec<MissingInclude>,
+    vpaths: Vec<(String, Vec<String>)>, // Search directories for files matching each pattern
+    functions: FunctionRegistry,
+    pub(crate) options: BuildOptions,
 }
 
 impl MakeFile {
     /// The crate internal constructor for a Makefile
     pub(crate) fn new(
-        var_map: HashMap<String, String>,
+        var_map: HashMap<String, Variable>,
         finalised_rules: Vec<FinalRule>,
         include_list: Vec<String>,
+        missing_includes: Vec<MissingInclude>,
+        vpaths: Vec<(String, Vec<String>)>,
+        functions: FunctionRegistry,
     ) -> Self {
+        let rule_index = finalised_rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| (rule.target.clone(), index))
+            .collect();
         MakeFile {
             var_map,
             finalised_rules,
+            rule_index,
             include_list,
+            missing_includes,
+            vpaths,
+            functions,
+            options: BuildOptions::default(),
         }
     }
 
-    /// Substitutes variables for their actual value
-    fn substitute_var(
-        &self,
-        it: &mut dyn Iterator<Item = char>,
-        target: &str,
-        deps: &[String],
-    ) -> String {
-        match it.next().expect("Unexpected end of variable, quitting!") {
-            '@' => target.to_owned(),
-            '?' => deps.iter().fold(String::new(), |res, dep| res + " " + dep),
-            '<' => deps.iter().next().unwrap_or(&String::new()).clone(),
-            '$' => String::from("$"),
-            // handle bracketed variables
-            '(' => get_var_trimmed(
-                &self.var_map,
-                read_bracketed_var(it, ")", |it| self.substitute_var(it, target, deps)),
-            ),
-            '{' => get_var_trimmed(
-                &self.var_map,
-                read_bracketed_var(it, "}", |it| self.substitute_var(it, target, deps)),
-            ),
-            x => panic!("${} ???", x),
-        }
+    /// makes circular dependencies an error, rather than dropping the
+    /// dependency which closes the cycle with a warning
+    pub fn set_strict_cycles(&mut self, strict: bool) {
+        self.options.strict_cycles = strict;
     }
 
-    /// Performs the build specified by the makefile.
-    fn build(&self, target: &FinalRule, silent: bool) -> SystemTime {
-        let mut newest_dep: SystemTime = SystemTime::UNIX_EPOCH;
-        for prereq in &target.prereqs {
-            if let Some(rule) = self.finalised_rules.iter().find(|r| r.target == *prereq) {
-                newest_dep = std::cmp::max(self.build(rule, silent), newest_dep);
-            } else if !Path::new(prereq).exists() {
-                panic!("No rule to build target \"{}\", stopping.", prereq);
-            } else {
-                newest_dep = std::cmp::max(
-                    std::fs::metadata(prereq).unwrap().modified().unwrap(),
-                    newest_dep,
-                );
-            }
-        }
+    /// sets how many recipes may be run at once, None meaning no limit,
+    /// rather than sharing the jobserver of a parent make
+    pub fn set_jobs(&mut self, jobs: Option<usize>) {
+        self.options.jobs = jobs;
+        self.options.jobs_given = true;
+    }
+
+    /// holds back new jobs while the load average is at least max_load,
+    /// None meaning no limit
+    pub fn set_max_load(&mut self, max_load: Option<f64>) {
+        self.options.max_load = max_load.filter(|max_load| *max_load > 0.0);
+    }
+
+    /// sets whether targets unaffected by a failure are still updated
+    pub fn set_keep_going(&mut self, keep_going: bool) {
+        self.options.keep_going = keep_going;
+    }
+
+    /// sets whether recipes carry on past lines which fail
+    pub fn set_ignore_errors(&mut self, ignore_errors: bool) {
+        self.options.ignore_errors = ignore_errors;
+    }
 
-        let modified = std::fs::metadata(&target.target)
-            .ok()
-            .map(|meta| meta.modified().ok())
-            .flatten();
+    /// sets whether recipes are only printed, apart from those running sub-makes
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.options.dry_run = dry_run;
+    }
+
+    /// sets whether out of date targets are touched rather than remade
+    pub fn set_touch(&mut self, touch: bool) {
+        self.options.touch = touch;
+    }
+
+    /// sets whether every target with a rule is remade, even if up to date
+    pub fn set_always_make(&mut self, always_make: bool) {
+        self.options.always_make = always_make;
+    }
+
+    /// treats a file as very old, so that it's never remade and nothing
+    /// is remade because of it
+    pub fn assume_old(&mut self, name: impl Into<String>) {
+        self.options.assume_old.push(name.into());
+    }
 
-        if Path::new(&target.target).exists() && &newest_dep < modified.as_ref().unwrap() {
-            return modified.unwrap();
+    /// treats a file as though it had just been modified
+    pub fn assume_new(&mut self, name: impl Into<String>) {
+        self.options.assume_new.push(name.into());
+    }
+
+    /// sets whether everything make knows is printed once goals are built
+    pub fn set_print_data_base(&mut self, print: bool) {
+        self.options.print_data_base = print;
+    }
+
+    /// sets how the output of jobs running at the same time is kept apart
+    pub fn set_output_sync(&mut self, sync: OutputSync) {
+        self.options.output_sync = sync;
+    }
+
+    /// sets how the jobserver shares its tokens with sub-makes
+    pub fn set_jobserver_style(&mut self, style: JobserverStyle) {
+        self.options.jobserver_style = style;
+    }
+
+    /// registers a function which can be called while expanding recipes,
+    /// see `MakeFileLoader::register_function`
+    pub fn register_function(
+        &mut self,
+        name: impl Into<String>,
+        function: impl MakeFunction + 'static,
+    ) -> std::io::Result<()> {
+        self.functions.register(name, function)
+    }
+
+    /// finds a file in the current directory, or otherwise in the directories
+    /// given by vpath directives and the VPATH variable
+    pub(crate) fn find_file(&self, name: &str) -> std::io::Result<Option<String>> {
+        if Path::new(name).exists() {
+            return Ok(Some(name.to_owned()));
+        }
+        if Path::new(name).is_absolute() {
+            return Ok(None);
         }
 
-        for recipe in &target.recipes {
-            let mut recipe_san = String::new();
-            let mut it = recipe.chars().peekable();
-            while let Some(c) = it.next() {
-                match c {
-                    '$' => {
-                        recipe_san.push_str(&self.substitute_var(
-                            &mut it,
-                            &target.target,
-                            &target.prereqs,
-                        ));
-                    }
-                    x => {
-                        recipe_san.push(x);
-                    }
-                }
-            }
+        let mut dirs: Vec<String> = self
+            .vpaths
+            .iter()
+            .filter(|(pattern, _)| matches(pattern, name))
+            .flat_map(|(_, dirs)| dirs.iter().cloned())
+            .collect();
+        dirs.extend(search_dirs(&self.variable("VPATH")?));
 
-            let mut recipe = recipe_san.trim();
-            let recipe_silent;
-            if recipe.starts_with('@') {
-                recipe = recipe[1..].trim();
-                recipe_silent = true;
-            } else {
-                recipe_silent = false;
-            }
+        Ok(dirs
+            .iter()
+            .map(|dir| format!("{}/{}", dir.trim_end_matches('/'), name))
+            .find(|path| Path::new(path).exists()))
+    }
 
-            if !silent && !recipe_silent {
-                println!("{}", recipe);
-            }
+    /// checks whether a target found along the search path is in a directory
+    /// listed in GPATH
+    pub(crate) fn in_gpath(&self, path: &str, name: &str) -> std::io::Result<bool> {
+        let dir = path[..path.len() - name.len()].trim_end_matches('/');
+        Ok(search_dirs(&self.variable("GPATH")?)
+            .iter()
+            .any(|gpath| gpath.trim_end_matches('/') == dir))
+    }
+
+    /// the expanded value of a variable outside of any recipe
+    pub(crate) fn variable(&self, name: &str) -> std::io::Result<String> {
+        get_var_trimmed(&mut RecipeScope::new(self, "", &[]), name)
+    }
 
-            let status = Command::new("sh")
-                .arg("-c")
-                .arg(recipe)
-                .status()
-                .expect("Failed to execute process");
+    /// Updates every makefile which was read, or couldn't be found, using the
+    /// rules to make it. Returns whether any of them changed, in which case
+    /// the makefiles should be read again.
+    pub fn remake_makefiles(&self, silent: bool) -> std::io::Result<bool> {
+        let names = self
+            .include_list
+            .iter()
+            .chain(self.missing_includes.iter().map(|include| &include.name));
+
+        let names: Vec<String> = names
+            .filter(|name| self.rule(name).is_some())
+            .cloned()
+            .collect();
+        let before: Vec<_> = names.iter().map(|name| modified_time(name)).collect();
+        let mut run = Run::new(self, self.options.for_makefiles(), silent);
+        run.update(&names)?;
 
-            if !status.success() {
-                panic!("Program exited with nonzero status, stopping.");
+        let remade = names
+            .iter()
+            .zip(before)
+            .any(|(name, before)| modified_time(run.path(name).unwrap_or(name)) != before);
+
+        if !remade {
+            if let Some(include) = self
+                .missing_includes
+                .iter()
+                .find(|include| !include.optional && !Path::new(&include.name).exists())
+            {
+                let message = format!("{}: No such file or directory", include.name);
+                return Err(located(error(message), include.location.as_ref()));
             }
         }
-        SystemTime::now()
+        Ok(remade)
     }
 
     /// Builds the default target
-    pub fn build_default(&self, silent: bool) {
-        let default_target = self.var_map.get(".DEFAULT_GOAL"); // Naming is consistent
-        let mut rule = None;
-        if let Some(default_target) = default_target {
-            rule = self
-                .finalised_rules
-                .iter()
-                .find(|rule| rule.target == *default_target);
-        }
-        if rule == None {
-            rule = self.finalised_rules.first();
-        }
-        if let Some(rule) = rule {
-            self.build(rule, silent);
-        } else {
-            panic!("No targets available, quitting!");
+    pub fn build_default(&self, silent: bool) -> std::io::Result<()> {
+        self.build_goals(&[], silent)
+    }
+
+    /// Builds each of the goals in turn, or the default goal if there are none
+    pub fn build_goals(&self, goals: &[String], silent: bool) -> std::io::Result<()> {
+        // goals share a run, so targets they have in common are only built once
+        let mut run = Run::new(self, self.options.clone(), silent);
+        let result = self
+            .goals_or_default(goals)
+            .and_then(|goals| run.update(&goals));
+        self.print_data_base(&run)?;
+        result
+    }
+
+    /// checks whether the goals, or the default goal if there are none, are
+    /// up to date, without running any recipes
+    pub fn is_up_to_date(&self, goals: &[String]) -> std::io::Result<bool> {
+        let options = BuildOptions {
+            question: true,
+            ..self.options.clone()
+        };
+        let mut run = Run::new(self, options, true);
+        let result = self
+            .goals_or_default(goals)
+            .and_then(|goals| run.update(&goals));
+        self.print_data_base(&run)?;
+        result.map(|()| !run.out_of_date())
+    }
+
+    /// prints everything make knows after a run, if asked to by -p
+    fn print_data_base(&self, run: &Run) -> std::io::Result<()> {
+        if !self.options.print_data_base {
+            return Ok(());
         }
+        print_data_base(self, run, &mut std::io::stdout().lock())
     }
 
     /// Builds a makefile target
-    pub fn build_target(&self, target: impl AsRef<str>, silent: bool) {
-        let rule = self
-            .finalised_rules
-            .iter()
-            .find(|rule| rule.target == target.as_ref());
+    pub fn build_target(&self, target: impl AsRef<str>, silent: bool) -> std::io::Result<()> {
+        self.build_goals(&[target.as_ref().to_owned()], silent)
+    }
 
-        if let Some(rule) = rule {
-            self.build(rule, silent);
+    /// the goals given, or the default goal if there are none
+    fn goals_or_default(&self, goals: &[String]) -> std::io::Result<Vec<String>> {
+        if goals.is_empty() {
+            Ok(vec![self.default_goal()?])
         } else {
-            panic!("No rule to make target {}, quitting!", target.as_ref());
+            Ok(goals.to_vec())
+        }
+    }
+
+    /// the goal built when none are given
+    fn default_goal(&self) -> std::io::Result<String> {
+        // the loader sets .DEFAULT_GOAL, but makefiles may change it
+        let goal = self.variable(".DEFAULT_GOAL")?;
+        match goal.split_whitespace().collect::<Vec<_>>()[..] {
+            [] => Err(located(error("No targets"), None)),
+            [goal] => Ok(goal.to_owned()),
+            _ => Err(located(
+                error(".DEFAULT_GOAL contains more than one target"),
+                None,
+            )),
+        }
+    }
+
+    /// every variable defined by the makefile or the environment
+    pub(crate) fn variables(&self) -> &HashMap<String, Variable> {
+        &self.var_map
+    }
+
+    /// every rule, in the order they were first defined
+    pub(crate) fn rules(&self) -> &[FinalRule] {
+        &self.finalised_rules
+    }
+
+    /// the search directories given by vpath directives, with their patterns
+    pub(crate) fn vpaths(&self) -> &[(String, Vec<String>)] {
+        &self.vpaths
+    }
+
+    /// looks up the rule for a target
+    pub(crate) fn rule(&self, target: &str) -> Option<&FinalRule> {
+        self.rule_index
+            .get(target)
+            .map(|&index| &self.finalised_rules[index])
+    }
+}
+
+/// The variables visible while expanding the recipes of a target
+pub(crate) struct RecipeScope<'a> {
+    makefile: &'a MakeFile,
+    target: &'a str,
+    deps: &'a [String],
+    defined: HashMap<String, Variable>, // Variables set while expanding, such as .SHELLSTATUS
+    bindings: Bindings,
+    pub(crate) location: Option<Location>,
+}
+
+impl<'a> RecipeScope<'a> {
+    pub(crate) fn new(makefile: &'a MakeFile, target: &'a str, deps: &'a [String]) -> Self {
+        Self {
+            makefile,
+            target,
+            deps,
+            defined: HashMap::new(),
+            bindings: Bindings::default(),
+            location: None,
+        }
+    }
+}
+
+impl Scope for RecipeScope<'_> {
+    fn lookup(&self, name: &str) -> Option<Variable> {
+        match name {
+            "@" => Some(Variable::simple(self.target, Origin::Automatic)),
+            "?" => Some(Variable::simple(
+                self.deps
+                    .iter()
+                    .fold(String::new(), |res, dep| res + " " + dep),
+                Origin::Automatic,
+            )),
+            "<" => Some(Variable::simple(
+                self.deps.first().cloned().unwrap_or_default(),
+                Origin::Automatic,
+            )),
+            "^" => {
+                let mut deps: Vec<&str> = Vec::new();
+                for dep in self.deps {
+                    if !deps.contains(&dep.as_str()) {
+                        deps.push(dep);
+                    }
+                }
+                Some(Variable::simple(deps.join(" "), Origin::Automatic))
+            }
+            "+" => Some(Variable::simple(self.deps.join(" "), Origin::Automatic)),
+            ".VARIABLES" => Some(variable_list(&self.makefile.var_map)),
+            name => self
+                .defined
+                .get(name)
+                .or_else(|| self.makefile.var_map.get(name))
+                .cloned(),
         }
     }
+
+    fn variable_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.makefile.var_map.keys().cloned().collect();
+        names.extend(
+            self.defined
+                .keys()
+                .filter(|name| !self.makefile.var_map.contains_key(*name))
+                .cloned(),
+        );
+        names
+    }
+
+    fn define(&mut self, name: &str, var: Variable) {
+        self.defined.insert(name.to_owned(), var);
+    }
+
+    fn bindings(&mut self) -> &mut Bindings {
+        &mut self.bindings
+    }
+
+    fn function(&self, name: &str) -> Option<Arc<dyn MakeFunction>> {
+        self.makefile.functions.get(name)
+    }
+
+    fn location(&self) -> Option<Location> {
+        self.location.clone()
+    }
+}
+
+/// when a file was last modified, or None if it doesn't exist
+fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// splits a search path on colons and whitespace
+pub(crate) fn search_dirs(path: &str) -> Vec<String> {
+    path.split(|c: char| c == ':' || c.is_whitespace())
+        .filter(|dir| !dir.is_empty())
+        .map(str::to_owned)
+        .collect()
 }
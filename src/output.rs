@@ -0,0 +1,145 @@
+use std::io::{self, Read, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+
+/// How the output of jobs running at the same time is kept apart
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum OutputSync {
+    #[default]
+    None, // Output is written as soon as it's produced
+    Line,    // Output is written once each recipe line has finished
+    Target,  // Output is written once each recipe has finished, apart from sub-makes
+    Recurse, // Output is written once each recipe has finished, including sub-makes
+}
+
+impl OutputSync {
+    /// the name of the mode, as given to --output-sync
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputSync::None => "none",
+            OutputSync::Line => "line",
+            OutputSync::Target => "target",
+            OutputSync::Recurse => "recurse",
+        }
+    }
+
+    /// looks up a mode by its name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(OutputSync::None),
+            "line" => Some(OutputSync::Line),
+            "target" => Some(OutputSync::Target),
+            "recurse" => Some(OutputSync::Recurse),
+            _ => None,
+        }
+    }
+
+    /// whether the output of a recipe line is captured, sub-makes doing
+    /// their own synchronising unless the whole invocation is kept together
+    pub(crate) fn captures(self, recursive: bool) -> bool {
+        match self {
+            OutputSync::None => false,
+            OutputSync::Target => !recursive,
+            OutputSync::Line | OutputSync::Recurse => true,
+        }
+    }
+}
+
+/// The output of a job which is held back until it can be written at once
+#[derive(Debug, Default)]
+pub(crate) struct Output {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    merged: bool, // Whether make's stdout and stderr are the same, so both go to stdout
+}
+
+impl Output {
+    pub(crate) fn new(merged: bool) -> Self {
+        Self {
+            merged,
+            ..Default::default()
+        }
+    }
+
+    /// holds back a line which would have been printed to stdout
+    pub(crate) fn println(&mut self, line: &str) {
+        self.stdout.extend_from_slice(line.as_bytes());
+        self.stdout.push(b'\n');
+    }
+
+    /// holds back a line which would have been printed to stderr
+    pub(crate) fn eprintln(&mut self, line: &str) {
+        let buffer = if self.merged {
+            &mut self.stdout
+        } else {
+            &mut self.stderr
+        };
+        buffer.extend_from_slice(line.as_bytes());
+        buffer.push(b'\n');
+    }
+
+    /// runs a command, holding back everything it writes, which is kept
+    /// byte for byte so that colours and the like survive
+    pub(crate) fn run(&mut self, command: &mut Command) -> io::Result<ExitStatus> {
+        if self.merged {
+            // a single pipe keeps stdout and stderr in the order they were written
+            let (mut reader, writer) = io::pipe()?;
+            command.stdout(writer.try_clone()?).stderr(writer);
+            let child = command.spawn();
+            // close make's copies of the pipe, so reading stops when the child exits
+            command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+            let mut child = child?;
+            reader.read_to_end(&mut self.stdout)?;
+            return child.wait();
+        }
+
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = command.spawn()?;
+        command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let stderr = thread::spawn(move || {
+            let mut buffer = Vec::new();
+            stderr.read_to_end(&mut buffer).map(|_| buffer)
+        });
+        if let Some(mut stdout) = child.stdout.take() {
+            stdout.read_to_end(&mut self.stdout)?;
+        }
+        self.stderr
+            .extend(stderr.join().expect("couldn't read stderr")?);
+        child.wait()
+    }
+
+    /// writes everything held back all at once
+    pub(crate) fn flush(&mut self) {
+        // nothing else can be done if make's own output is broken
+        if !self.stdout.is_empty() {
+            let mut stdout = io::stdout().lock();
+            let _ = stdout.write_all(&self.stdout);
+            let _ = stdout.flush();
+            self.stdout.clear();
+        }
+        if !self.stderr.is_empty() {
+            let mut stderr = io::stderr().lock();
+            let _ = stderr.write_all(&self.stderr);
+            self.stderr.clear();
+        }
+    }
+}
+
+/// checks whether make's stdout and stderr are the same file, such as a terminal
+#[cfg(unix)]
+pub(crate) fn same_output() -> bool {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata("/dev/stdout"), fs::metadata("/dev/stderr")) {
+        (Ok(stdout), Ok(stderr)) => stdout.dev() == stderr.dev() && stdout.ino() == stderr.ino(),
+        _ => false,
+    }
+}
+
+/// without a way to tell, stdout and stderr are kept apart
+#[cfg(not(unix))]
+pub(crate) fn same_output() -> bool {
+    false
+}
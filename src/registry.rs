@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use crate::expand::{error, expand, get_var_trimmed, get_variable, Scope};
+use crate::functions::is_builtin;
+use crate::variables::{Origin, Variable};
+
+/// A make function implemented in Rust, which is called as `$(name args)`
+pub trait MakeFunction: Send + Sync {
+    /// calls the function with its comma separated arguments
+    fn call(&self, scope: &mut FunctionScope, args: &[String]) -> io::Result<String>;
+
+    /// the fewest arguments the function can be called with
+    fn min_args(&self) -> usize {
+        0
+    }
+
+    /// the most arguments the function takes, the last of which keeps any
+    /// further commas, zero means unlimited
+    fn max_args(&self) -> usize {
+        0
+    }
+
+    /// whether the arguments are expanded before the function is called,
+    /// otherwise they're passed as written and can be expanded lazily
+    fn expand_args(&self) -> bool {
+        true
+    }
+}
+
+impl<F> MakeFunction for F
+where
+    F: Fn(&mut FunctionScope, &[String]) -> io::Result<String> + Send + Sync,
+{
+    fn call(&self, scope: &mut FunctionScope, args: &[String]) -> io::Result<String> {
+        self(scope, args)
+    }
+}
+
+/// The variables visible to a function while it is being called
+pub struct FunctionScope<'a> {
+    scope: &'a mut dyn Scope,
+}
+
+impl<'a> FunctionScope<'a> {
+    pub(crate) fn new(scope: &'a mut dyn Scope) -> Self {
+        Self { scope }
+    }
+
+    /// the value of a variable, expanded and trimmed, which is empty if the
+    /// variable isn't defined
+    pub fn value(&mut self, name: &str) -> io::Result<String> {
+        get_var_trimmed(self.scope, name)
+    }
+
+    /// checks whether a variable is defined
+    pub fn is_defined(&mut self, name: &str) -> bool {
+        get_variable(self.scope, name).is_some()
+    }
+
+    /// expands every variable and function reference in a piece of text
+    pub fn expand(&mut self, text: &str) -> io::Result<String> {
+        expand(self.scope, text)
+    }
+
+    /// defines a simply expanded variable
+    pub fn define(&mut self, name: &str, value: impl Into<String>) {
+        self.scope
+            .define(name, Variable::simple(value, Origin::File));
+    }
+
+    /// the file name and line of the makefile currently being expanded
+    pub fn location(&self) -> Option<(String, usize)> {
+        self.scope.location()
+    }
+}
+
+/// The functions registered by an embedder, which are called alongside
+/// the built-in functions
+#[derive(Clone, Default)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, Arc<dyn MakeFunction>>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// registers a function, replacing any registered under the same name
+    /// built-in functions can't be replaced
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        function: impl MakeFunction + 'static,
+    ) -> io::Result<()> {
+        let name = name.into();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(error(format!("Invalid function name: '{}'", name)));
+        }
+        if is_builtin(&name) {
+            return Err(error(format!("Cannot redefine function '{}'", name)));
+        }
+        self.functions.insert(name, Arc::new(function));
+        Ok(())
+    }
+
+    /// looks up a registered function
+    pub(crate) fn get(&self, name: &str) -> Option<Arc<dyn MakeFunction>> {
+        self.functions.get(name).cloned()
+    }
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+impl PartialEq for FunctionRegistry {
+    fn eq(&self, other: &Self) -> bool {
+        self.functions.len() == other.functions.len()
+            && self.functions.iter().all(|(name, function)| {
+                other
+                    .functions
+                    .get(name)
+                    .is_some_and(|other| Arc::ptr_eq(function, other))
+            })
+    }
+}
+
+impl Eq for FunctionRegistry {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expand::Bindings;
+
+    /// A scope holding variables and the functions of a registry
+    #[derive(Default)]
+    struct TestScope {
+        variables: HashMap<String, Variable>,
+        bindings: Bindings,
+        functions: FunctionRegistry,
+    }
+
+    impl Scope for TestScope {
+        fn lookup(&self, name: &str) -> Option<Variable> {
+            self.variables.get(name).cloned()
+        }
+
+        fn variable_names(&self) -> Vec<String> {
+            self.variables.keys().cloned().collect()
+        }
+
+        fn define(&mut self, name: &str, var: Variable) {
+            self.variables.insert(name.to_owned(), var);
+        }
+
+        fn bindings(&mut self) -> &mut Bindings {
+            &mut self.bindings
+        }
+
+        fn function(&self, name: &str) -> Option<Arc<dyn MakeFunction>> {
+            self.functions.get(name)
+        }
+    }
+
+    /// A function which takes one or two arguments as written
+    struct Quote;
+
+    impl MakeFunction for Quote {
+        fn call(&self, _: &mut FunctionScope, args: &[String]) -> io::Result<String> {
+            Ok(format!("'{}'", args.join("' '")))
+        }
+
+        fn min_args(&self) -> usize {
+            1
+        }
+
+        fn max_args(&self) -> usize {
+            2
+        }
+
+        fn expand_args(&self) -> bool {
+            false
+        }
+    }
+
+    fn scope() -> TestScope {
+        let mut scope = TestScope::default();
+        scope.define("who", Variable::simple("world", Origin::File));
+        scope
+            .functions
+            .register("greet", |scope: &mut FunctionScope, args: &[String]| {
+                let who = match args.first() {
+                    Some(arg) => arg.clone(),
+                    None => scope.value("who")?,
+                };
+                scope.define("greeted", who.clone());
+                Ok(format!("hello {}", who))
+            })
+            .unwrap();
+        scope.functions.register("quote", Quote).unwrap();
+        scope
+    }
+
+    #[test]
+    fn registered_functions_are_called() {
+        let mut scope = scope();
+        let cases = [
+            ("$(greet)", "hello world"),
+            ("$(greet $(who)s)", "hello worlds"),
+            ("${greet}", "hello world"),
+            ("$(quote $(who),a,b)", "'$(who)' 'a,b'"),
+            ("$(greet) $(greeted)", "hello world world"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(expand(&mut scope, text).unwrap(), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn registered_functions_check_their_arguments() {
+        let mut scope = scope();
+        let err = expand(&mut scope, "$(quote)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "insufficient number of arguments (0) to function 'quote'"
+        );
+        // anything else is a variable reference
+        assert_eq!(expand(&mut scope, "$(unknown x)").unwrap(), "");
+    }
+
+    #[test]
+    fn builtin_and_invalid_names_are_refused() {
+        let mut registry = FunctionRegistry::new();
+        let err = registry.register("subst", Quote).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot redefine function 'subst'");
+        let err = registry.register("two words", Quote).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid function name: 'two words'");
+        assert!(registry.register("", Quote).is_err());
+        assert_eq!(registry, FunctionRegistry::new());
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+
+/// How a variable's value is treated when it is referenced
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Flavor {
+    Recursive, // Defined with =, expanded every time it is referenced
+    Simple,    // Defined with := or ::=, expanded once when it was defined
+}
+
+/// Where a variable's definition came from
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Origin {
+    Default,     // Built into make, such as CC
+    Environment, // Imported from the environment make was run in
+    File,        // Defined in a makefile
+    Override,    // Set by make regardless of the makefile, such as .SHELLSTATUS
+    Automatic,   // Defined while expanding, such as $@ or the arguments to call
+}
+
+impl fmt::Display for Flavor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Flavor::Recursive => "recursive",
+            Flavor::Simple => "simple",
+        })
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Origin::Default => "default",
+            Origin::Environment => "environment",
+            Origin::File => "file",
+            Origin::Override => "override",
+            Origin::Automatic => "automatic",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Variable {
+    value: String,
+    flavor: Flavor,
+    origin: Origin,
+    exported: bool, // Whether it is placed in the environment of commands
+}
+
+impl Variable {
+    // constructors
+    pub(crate) fn new(value: String, flavor: Flavor, origin: Origin) -> Self {
+        Self {
+            value,
+            flavor,
+            origin,
+            exported: false,
+        }
+    }
+    pub(crate) fn simple(value: impl Into<String>, origin: Origin) -> Self {
+        Self::new(value.into(), Flavor::Simple, origin)
+    }
+
+    // read only member access
+    pub(crate) fn value(&self) -> &str {
+        &self.value
+    }
+    pub(crate) fn flavor(&self) -> Flavor {
+        self.flavor
+    }
+    pub(crate) fn origin(&self) -> Origin {
+        self.origin
+    }
+    pub(crate) fn exported(&self) -> bool {
+        self.exported
+    }
+
+    // mutable member access
+    pub(crate) fn set_exported(&mut self, exported: bool) {
+        self.exported = exported;
+    }
+}
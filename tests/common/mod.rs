@@ -0,0 +1,86 @@
+#![allow(dead_code)] // Each test crate uses a different part of these helpers
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::time::{Duration, SystemTime};
+
+/// A directory holding a makefile, removed once the test is done with it
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new(name: &str, makefile: &str) -> Self {
+        let path = env::temp_dir().join(format!("lc-make-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("Makefile"), makefile).unwrap();
+        Self { path }
+    }
+
+    /// runs make in the directory, away from any make running the tests
+    pub fn make(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    /// a make command which runs in the directory, for tests which need to
+    /// set up its environment further
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_lc-make"));
+        command
+            .args(args)
+            .current_dir(&self.path)
+            .env_remove("MAKEFLAGS")
+            .env_remove("MFLAGS")
+            .env_remove("MAKELEVEL")
+            .env_remove("MAKEFILES");
+        command
+    }
+
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// writes a file in the directory, creating any directories it's in
+    pub fn write(&self, name: &str, contents: &str) {
+        let path = self.file(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// writes a file which was last modified the given number of seconds ago
+    pub fn write_aged(&self, name: &str, contents: &str, age: u64) {
+        self.write(name, contents);
+        let modified = SystemTime::now() - Duration::from_secs(age);
+        fs::File::options()
+            .write(true)
+            .open(self.file(name))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    pub fn read(&self, name: &str) -> String {
+        fs::read_to_string(self.file(name)).unwrap()
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.file(name).exists()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
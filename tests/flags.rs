@@ -0,0 +1,547 @@
+mod common;
+
+use std::fmt::Write;
+
+use common::{stderr, stdout, TestDir};
+use lc_make::loader::MakeFileLoader;
+use lc_make::makefile::BuildError;
+
+#[test]
+fn jobs_run_recipes_concurrently() {
+    // each recipe waits for the other to start, which only finishes when
+    // both run at once
+    let dir = TestDir::new(
+        "jobs",
+        "all: a b\n\
+         a b:\n\
+         \t@touch $@.started; for i in $$(seq 500); do [ -f $(if $(filter a,$@),b,a).started ] && break; sleep 0.01; done; [ -f $(if $(filter a,$@),b,a).started ]\n",
+    );
+    let output = dir.make(&["-j2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn serial_run_finishes_each_target_before_the_next() {
+    let dir = TestDir::new(
+        "serial",
+        "all: a b\n\
+         a:\n\
+         \t@sleep 0.2; echo a\n\
+         b:\n\
+         \t@echo b\n",
+    );
+    assert_eq!(stdout(&dir.make(&[])), "a\nb\n");
+    assert_eq!(stdout(&dir.make(&["-j2"])), "b\na\n");
+}
+
+#[test]
+fn wait_and_not_parallel_order_prerequisites() {
+    let rules = "a:\n\
+                 \t@sleep 0.2; echo a\n\
+                 b:\n\
+                 \t@echo b\n";
+    let cases = [
+        "all: a .WAIT b\n",
+        ".NOTPARALLEL:\nall: a b\n",
+        ".NOTPARALLEL: all\nall: a b\n",
+    ];
+    for makefile in cases {
+        let dir = TestDir::new("wait", &format!("{}{}", makefile, rules));
+        assert_eq!(stdout(&dir.make(&["-j"])), "a\nb\n", "{}", makefile);
+    }
+}
+
+#[test]
+fn failures_wait_for_unfinished_jobs() {
+    let dir = TestDir::new(
+        "unfinished",
+        "all: a b\n\
+         a:\n\
+         \t@exit 3\n\
+         b:\n\
+         \t@sleep 0.2; touch b\n",
+    );
+    let output = dir.make(&["-j2"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(dir.exists("b"));
+    assert_eq!(
+        stderr(&output),
+        "lc-make: *** Waiting for unfinished jobs....\n\
+         lc-make: *** [Makefile:3: a] Error 3\n"
+    );
+}
+
+#[test]
+fn failures_report_the_line_of_the_recipe() {
+    let dir = TestDir::new(
+        "recipe-line",
+        "a:\n\
+         \t@echo one\n\
+         # a comment\n\
+         \n\
+         \t@echo two \\\n\
+         \t  three\n\
+         \t@exit 4\n",
+    );
+    let output = dir.make(&[]);
+    assert_eq!(stdout(&output), "one\ntwo three\n");
+    assert_eq!(stderr(&output), "lc-make: *** [Makefile:7: a] Error 4\n");
+}
+
+#[test]
+fn failures_carry_the_target_and_exit_status() {
+    let dir = TestDir::new("build-error", "all: a\na:\n\t@exit 5\n");
+    let mut loader = MakeFileLoader::new();
+    loader.load_file(dir.file("Makefile")).unwrap();
+    let err = loader
+        .finalise()
+        .build_goals(&[String::from("all")], true)
+        .unwrap_err();
+    let err = BuildError::find(&err).unwrap();
+    assert_eq!(err.target(), Some("a"));
+    assert_eq!(err.status(), Some(5));
+    let makefile = dir.file("Makefile").display().to_string();
+    let expected = format!("lc-make: *** [{}:3: a] Error 5", makefile);
+    assert_eq!(err.to_string(), expected);
+}
+
+#[test]
+fn long_chains_of_prerequisites_are_polled_without_recursing() {
+    const DEPTH: usize = 20000;
+    let mut makefile = String::new();
+    for i in 0..DEPTH {
+        writeln!(makefile, "t{}: t{}", i, i + 1).unwrap();
+    }
+    writeln!(makefile, "t{}:\n\t@$(if $(FAIL),exit 1,:)", DEPTH).unwrap();
+    let dir = TestDir::new("chain", &makefile);
+
+    let output = dir.make(&["-j"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = dir.command(&[]).env("FAIL", "1").output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let expected = format!(
+        "lc-make: *** [Makefile:{}: t{}] Error 1\n",
+        DEPTH + 2,
+        DEPTH
+    );
+    assert_eq!(stderr(&output), expected);
+}
+
+#[test]
+fn cycles_through_waiting_targets_are_found() {
+    // t only polls a once s has finished, when a is waiting on it through x
+    let dir = TestDir::new(
+        "waiting-cycle",
+        "a: s x\n\
+         \t@echo a\n\
+         s:\n\
+         \t@sleep 0.1\n\
+         x: t\n\
+         \t@echo x\n\
+         t: s .WAIT a\n\
+         \t@echo t\n",
+    );
+    let output = dir.make(&["-j"]);
+    assert_eq!(stdout(&output), "t\nx\na\n");
+    assert_eq!(
+        stderr(&output),
+        "lc-make: Circular t <- a dependency dropped.\n"
+    );
+
+    let output = dir.make(&["-j", "--strict-cycles"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        stderr(&output),
+        "lc-make: *** Circular dependency a <- x <- t <- a.  Stop.\n"
+    );
+}
+
+#[test]
+fn sub_makes_share_the_jobserver() {
+    // the sub-make runs its recipes at once only with a token from its parent
+    let sub = "all: a b\n\
+               a b:\n\
+               \t@touch $@.started; for i in $$(seq 500); do [ -f $(if $(filter a,$@),b,a).started ] && break; sleep 0.01; done; [ -f $(if $(filter a,$@),b,a).started ]\n";
+    for style in ["fifo", "pipe"] {
+        let dir = TestDir::new("jobserver", "all:\n\t@$(MAKE) -f sub.mk\n");
+        dir.write("sub.mk", sub);
+        let style = format!("--jobserver-style={}", style);
+        let output = dir.make(&["-j2", &style]);
+        assert!(output.status.success(), "{}: {}", style, stderr(&output));
+    }
+}
+
+#[test]
+fn jobserver_is_advertised_in_makeflags() {
+    let dir = TestDir::new("makeflags", "all:\n\t@echo \"$$MAKEFLAGS\"\n");
+    let output = dir.make(&["-j3", "--jobserver-style=pipe"]);
+    let makeflags = stdout(&output);
+    assert!(
+        makeflags.starts_with("-j3 --jobserver-auth="),
+        "{}",
+        makeflags
+    );
+    assert_eq!(stdout(&dir.make(&[])), "\n");
+}
+
+#[test]
+fn unknown_jobserver_styles_are_errors() {
+    let dir = TestDir::new("jobserver-style", "all:\n\t@echo all\n");
+    let output = dir.make(&["-j2", "--jobserver-style=socket"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        stderr(&output),
+        "lc-make: *** unknown jobserver auth style 'socket'.  Stop.\n"
+    );
+}
+
+#[test]
+fn load_limit_holds_back_new_jobs() {
+    // a job started moments ago is guessed to add to the load, so a tiny
+    // limit keeps a second job from starting alongside the first
+    let dir = TestDir::new(
+        "max-load",
+        "all: a b\n\
+         a:\n\
+         \t@sleep 0.2; echo a\n\
+         b:\n\
+         \t@echo b\n",
+    );
+    assert_eq!(stdout(&dir.make(&["-j2", "-l0.001"])), "a\nb\n");
+    assert_eq!(stdout(&dir.make(&["-j2", "-l", "0.001"])), "a\nb\n");
+    assert_eq!(stdout(&dir.make(&["-j2", "-l0.001", "-l"])), "b\na\n");
+}
+
+#[test]
+fn output_sync_keeps_the_output_of_each_target_together() {
+    let dir = TestDir::new(
+        "output-sync",
+        "all: a b\n\
+         a:\n\
+         \t@echo a1\n\
+         \t@sleep 0.4; echo a2\n\
+         b:\n\
+         \t@sleep 0.2; echo b1\n\
+         line:\n\
+         \t@echo line\n",
+    );
+    assert_eq!(stdout(&dir.make(&["-j2"])), "a1\nb1\na2\n");
+    assert_eq!(stdout(&dir.make(&["-j2", "-Oline"])), "a1\nb1\na2\n");
+    for args in [
+        &["-j2", "-O"][..],
+        &["-j2", "-Otarget"],
+        &["-j2", "--output-sync", "target"],
+    ] {
+        assert_eq!(stdout(&dir.make(args)), "b1\na1\na2\n", "{:?}", args);
+    }
+
+    // a bare -O doesn't take the goal after it as its type
+    assert_eq!(stdout(&dir.make(&["-O", "line"])), "line\n");
+}
+
+#[test]
+fn output_sync_is_passed_to_sub_makes() {
+    let dir = TestDir::new("output-sync-makeflags", "all:\n\t@echo \"$$MAKEFLAGS\"\n");
+    assert_eq!(stdout(&dir.make(&["-s", "-Oline"])), "s -Oline\n");
+    let output = dir.make(&["-Ofoo"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        stderr(&output),
+        "lc-make: *** unknown output-sync type 'foo'.  Stop.\n"
+    );
+}
+
+#[test]
+fn keep_going_updates_targets_unaffected_by_failures() {
+    let dir = TestDir::new(
+        "keep-going",
+        "all: a b c\n\
+         a:\n\
+         \t@exit 3\n\
+         b: missing\n\
+         \t@echo b\n\
+         c:\n\
+         \t@echo c\n",
+    );
+    let output = dir.make(&["-k"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "c\n");
+    assert_eq!(
+        stderr(&output),
+        "lc-make: *** [Makefile:3: a] Error 3\n\
+         lc-make: *** No rule to make target 'missing', needed by 'b'.\n\
+         lc-make: Target 'all' not remade because of errors.\n\
+         lc-make: *** Failed targets:\n\
+         lc-make: ***   a (exit status 3)\n\
+         lc-make: ***   missing\n"
+    );
+
+    // -S cancels -k, including one passed down by a parent make
+    let output = dir.make(&["-k", "-S"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), "lc-make: *** [Makefile:3: a] Error 3\n");
+}
+
+#[test]
+fn missing_targets_stop_make_unless_keeping_going() {
+    let dir = TestDir::new("no-rule", "all:\n\t@echo all\n");
+    let output = dir.make(&["missing", "all"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "lc-make: *** No rule to make target 'missing'.  Stop.\n"
+    );
+
+    let output = dir.make(&["-k", "missing", "all"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "all\n");
+}
+
+#[test]
+fn keep_going_is_passed_to_sub_makes() {
+    let dir = TestDir::new("keep-going-makeflags", "all:\n\t@echo \"$$MAKEFLAGS\"\n");
+    assert_eq!(stdout(&dir.make(&["-k", "-s"])), "ks\n");
+    let mut command = dir.command(&["-S"]);
+    command.env("MAKEFLAGS", "k");
+    assert_eq!(stdout(&command.output().unwrap()), "\n");
+}
+
+#[test]
+fn ignored_errors_let_recipes_carry_on() {
+    let dir = TestDir::new(
+        "ignore-errors",
+        "all:\n\
+         \t-@exit 2\n\
+         \t@false\n\
+         \t@echo done\n",
+    );
+    let output = dir.make(&[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "lc-make: [Makefile:2: all] Error 2 (ignored)\n\
+         lc-make: *** [Makefile:3: all] Error 1\n"
+    );
+
+    // -i ignores the errors of every line, and is passed to sub-makes
+    let output = dir.make(&["-i"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "done\n");
+    assert_eq!(
+        stderr(&output),
+        "lc-make: [Makefile:2: all] Error 2 (ignored)\n\
+         lc-make: [Makefile:3: all] Error 1 (ignored)\n"
+    );
+    let mut command = dir.command(&[]);
+    command.env("MAKEFLAGS", "i");
+    assert!(command.output().unwrap().status.success());
+}
+
+#[test]
+fn dry_run_prints_every_line_without_running_it() {
+    let dir = TestDir::new(
+        "dry-run",
+        "all: recurse\n\
+         \t@echo quiet\n\
+         \ttouch made\n\
+         recurse:\n\
+         \t@+cd sub && $(MAKE) -s\n",
+    );
+    dir.write("sub/Makefile", "all:\n\t@touch made\n");
+    for args in [&["-n"][..], &["-ns"]] {
+        let output = dir.make(args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(
+            stdout(&output),
+            format!(
+                "cd sub && {} -s\n\
+                 touch made\n\
+                 echo quiet\n\
+                 touch made\n",
+                env!("CARGO_BIN_EXE_lc-make")
+            )
+        );
+        assert!(!dir.exists("made") && !dir.exists("sub/made"));
+    }
+}
+
+#[test]
+fn dry_run_still_remakes_makefiles() {
+    let dir = TestDir::new(
+        "dry-run-include",
+        "include inc.mk\n\
+         all:\n\
+         \t@echo $(x)\n\
+         inc.mk:\n\
+         \t@echo x = included > $@\n",
+    );
+    let output = dir.make(&["-n"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "echo included\n");
+    assert!(dir.exists("inc.mk"));
+}
+
+#[test]
+fn touch_marks_targets_up_to_date_without_remaking_them() {
+    let dir = TestDir::new(
+        "touch",
+        ".PHONY: phony\n\
+         all: out phony\n\
+         out: in\n\
+         \t@echo building; cp in out\n\
+         phony:\n\
+         \t@echo phony\n",
+    );
+    dir.write("in", "in\n");
+    let output = dir.make(&["-t"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // phony targets aren't files, so they're never touched
+    assert_eq!(stdout(&output), "touch out\n");
+    assert_eq!(dir.read("out"), "");
+    assert!(!dir.exists("phony"));
+
+    // -n only prints what -t would touch
+    dir.write_aged("out", "", 10);
+    assert_eq!(stdout(&dir.make(&["-nt"])), "touch out\n");
+    assert_eq!(dir.make(&["-q", "out"]).status.code(), Some(1));
+}
+
+#[test]
+fn question_reports_whether_targets_are_out_of_date() {
+    let dir = TestDir::new(
+        "question",
+        ".PHONY: clean\n\
+         all: out\n\
+         out: in\n\
+         \t@echo building; cp in out\n\
+         clean:\n\
+         \t@rm -f out\n",
+    );
+    dir.write_aged("in", "in\n", 10);
+    let output = dir.make(&["-q"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    assert!(!dir.exists("out"));
+
+    dir.make(&[]);
+    assert_eq!(dir.make(&["-q"]).status.code(), Some(0));
+
+    // phony targets are always out of date, even when a file has their name
+    dir.write("clean", "");
+    assert_eq!(dir.make(&["-q", "clean"]).status.code(), Some(1));
+    assert!(dir.exists("out"));
+}
+
+#[test]
+fn always_make_and_assumed_files_force_or_prevent_rebuilds() {
+    let dir = TestDir::new(
+        "assume",
+        "out: mid\n\
+         \t@echo out; touch out\n\
+         mid: in\n\
+         \t@echo mid; touch mid\n",
+    );
+    dir.write_aged("in", "", 30);
+    dir.write_aged("mid", "", 20);
+    dir.write_aged("out", "", 10);
+    assert_eq!(stdout(&dir.make(&[])), "");
+    assert_eq!(
+        stdout(&dir.make(&["-n", "-B"])),
+        "echo mid; touch mid\necho out; touch out\n"
+    );
+    assert_eq!(
+        stdout(&dir.make(&["-n", "-W", "in"])),
+        "echo mid; touch mid\necho out; touch out\n"
+    );
+    assert_eq!(
+        stdout(&dir.make(&["-n", "--what-if=mid"])),
+        "echo out; touch out\n"
+    );
+
+    // a file assumed old is never remade, and nothing is remade because of it
+    dir.write("in", "");
+    assert_eq!(stdout(&dir.make(&["-o", "mid"])), "");
+    assert_eq!(stdout(&dir.make(&[])), "mid\nout\n");
+}
+
+#[test]
+fn print_data_base_lists_variables_rules_and_files() {
+    let dir = TestDir::new(
+        "data-base",
+        "x := 1\n\
+         define lines\n\
+         one\n\
+         two\n\
+         endef\n\
+         all: in\n\
+         \t@echo all\n\
+         %.o: %.c\n\
+         \tcc -c $<\n",
+    );
+    dir.write("in", "");
+    let output = dir.make(&["-p"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let out = stdout(&output);
+    assert!(
+        out.starts_with("all\n\n# Make data base, printed on "),
+        "{}",
+        out
+    );
+    assert!(out.contains("\n# makefile\nx := 1\n"), "{}", out);
+    assert!(
+        out.contains("\n# makefile\ndefine lines =\none\ntwo\nendef\n"),
+        "{}",
+        out
+    );
+    // without implicit rules, rules with patterns are listed with the files
+    assert!(
+        out.contains("\n# Implicit Rules\n\n# No implicit rules.\n\n# Files\n"),
+        "{}",
+        out
+    );
+    assert!(out.contains(
+        "\n# Not a target:\n\
+         in:\n\
+         #  Last modified "
+    ));
+    assert!(out.contains(
+        "\nall: in\n\
+         #  File does not exist.\n\
+         #  File has been updated.\n\
+         #  Successfully updated.\n\
+         #  recipe to execute (from 'Makefile', line 7):\n\
+         \t@echo all\n"
+    ));
+    assert!(out.contains(
+        "\n%.o: %.c\n\
+         #  File does not exist.\n\
+         #  File has not been updated.\n\
+         #  recipe to execute (from 'Makefile', line 9):\n\
+         \tcc -c $<\n\n\
+         # 2 files.\n"
+    ));
+    assert!(out.contains("\n# No 'vpath' search paths.\n"), "{}", out);
+    assert!(out.contains("\n# Finished Make data base on "), "{}", out);
+}
+
+#[test]
+fn print_data_base_with_question_runs_nothing() {
+    let dir = TestDir::new("data-base-question", "all:\n\t@echo all\n");
+    let output = dir.make(&["-pq"]);
+    assert_eq!(output.status.code(), Some(1));
+    let out = stdout(&output);
+    assert!(
+        out.starts_with("\n# Make data base, printed on "),
+        "{}",
+        out
+    );
+    assert!(out.contains(
+        "\nall:\n\
+         #  File does not exist.\n\
+         #  File has been updated.\n\
+         #  Successfully updated.\n"
+    ));
+}
@@ -0,0 +1,172 @@
+mod common;
+
+use common::{stderr, stdout, TestDir};
+
+#[test]
+fn prerequisites_are_found_along_the_search_path() {
+    let dir = TestDir::new(
+        "vpath",
+        "vpath %.c src\n\
+         VPATH = other\n\
+         prog: main.c util.h\n\
+         \t@echo $^ > $@\n",
+    );
+    dir.write("src/main.c", "");
+    dir.write("other/util.h", "");
+    let output = dir.make(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(dir.read("prog"), "src/main.c other/util.h\n");
+}
+
+#[test]
+fn out_of_date_targets_are_rebuilt_where_gpath_says() {
+    let makefile = "VPATH = lib\n\
+                    lib.o: lib.c\n\
+                    \t@echo $@\n";
+    for (gpath, built) in [("", "lib.o\n"), ("GPATH = lib\n", "lib/lib.o\n")] {
+        let dir = TestDir::new("gpath", &format!("{}{}", gpath, makefile));
+        dir.write_aged("lib/lib.o", "", 60);
+        dir.write("lib/lib.c", "");
+        let output = dir.make(&[]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stdout(&output), built);
+    }
+}
+
+#[test]
+fn includes_are_globbed_and_searched_for() {
+    let dir = TestDir::new(
+        "include",
+        "include parts/*.mk\n\
+         -include missing.mk\n\
+         sinclude also-missing.mk\n\
+         include inc.mk\n\
+         all:\n\
+         \t@echo $(A) $(B) $(C) $(D) $(.INCLUDE_DIRS)\n",
+    );
+    dir.write("parts/a.mk", "A = a\n");
+    dir.write("parts/b.mk", "B = b\n");
+    dir.write("incdir/inc.mk", "C = c\n");
+    dir.write("env.mk", "D = d\n");
+    let output = dir
+        .command(&["-I", "incdir"])
+        .env("MAKEFILES", "env.mk missing-env.mk")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("a b c d incdir"));
+}
+
+#[test]
+fn missing_includes_are_errors() {
+    let dir = TestDir::new("include-missing", "x = 1\ninclude missing.mk\n");
+    let output = dir.make(&[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        stderr(&output),
+        "Makefile:2: *** missing.mk: No such file or directory.  Stop.\n"
+    );
+}
+
+#[test]
+fn included_makefiles_are_remade_and_reread() {
+    let dir = TestDir::new(
+        "restart",
+        "include gen.mk\n\
+         all:\n\
+         \t@echo $(X) restarts=$(MAKE_RESTARTS)\n\
+         gen.mk: gen.in\n\
+         \t@cp gen.in $@\n",
+    );
+    dir.write("gen.in", "X = generated\n");
+    let output = dir.make(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "generated restarts=1\n");
+
+    // once up to date the makefiles are only read once
+    let output = dir.make(&[]);
+    assert_eq!(stdout(&output), "generated restarts=\n");
+}
+
+#[test]
+fn default_goal_is_the_first_ordinary_target() {
+    let dir = TestDir::new(
+        "default-goal",
+        ".PHONY: first second\n\
+         %.o: %.c\n\
+         \t@echo pattern\n\
+         first:\n\
+         \t@echo first\n\
+         second:\n\
+         \t@echo second\n",
+    );
+    assert_eq!(stdout(&dir.make(&[])), "first\n");
+    // goals are built in the order given
+    assert_eq!(stdout(&dir.make(&["second", "first"])), "second\nfirst\n");
+}
+
+#[test]
+fn default_goal_can_be_set_by_the_makefile() {
+    let dir = TestDir::new(
+        "default-goal-set",
+        "first:\n\
+         \t@echo first\n\
+         second:\n\
+         \t@echo second\n\
+         .DEFAULT_GOAL := second\n",
+    );
+    assert_eq!(stdout(&dir.make(&[])), "second\n");
+
+    let dir = TestDir::new("default-goal-empty", "x = 1\n");
+    let output = dir.make(&[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stderr(&output), "lc-make: *** No targets.  Stop.\n");
+}
+
+#[test]
+fn targets_are_only_updated_once_per_run() {
+    let dir = TestDir::new(
+        "once",
+        "all: a b\n\
+         a: common\n\
+         \t@echo a\n\
+         b: common\n\
+         \t@echo b\n\
+         common:\n\
+         \t@echo common\n\
+         b: extra\n\
+         extra:\n\
+         \t@echo extra\n",
+    );
+    assert_eq!(stdout(&dir.make(&[])), "common\na\nextra\nb\n");
+    // goals share the run too
+    assert_eq!(stdout(&dir.make(&["a", "b", "a"])), "common\na\nextra\nb\n");
+}
+
+#[test]
+fn circular_dependencies_are_dropped() {
+    let dir = TestDir::new(
+        "cycle",
+        "a: b\n\
+         \t@echo a\n\
+         b: c\n\
+         \t@echo b\n\
+         c: a\n\
+         \t@echo c\n",
+    );
+    let output = dir.make(&[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "c\nb\na\n");
+    assert_eq!(
+        stderr(&output),
+        "lc-make: Circular c <- a dependency dropped.\n"
+    );
+
+    let output = dir.make(&["--strict-cycles"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        stderr(&output),
+        "lc-make: *** Circular dependency a <- b <- c <- a.  Stop.\n"
+    );
+}